@@ -0,0 +1,360 @@
+use crate::error::{ProgramError, Result};
+use crate::rfc2217;
+use crate::telnet::{
+    self, Decoded, TelnetEvent, TelnetOptions, TelnetParser, OPT_BINARY, OPT_COM_PORT, OPT_ECHO,
+    OPT_SGA,
+};
+use crate::transport::{LineCommand, LineSettings};
+use futures::{future::FutureExt, pin_mut, select};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_util::bytes::Bytes;
+
+/// How many TCP clients may be connected to the bridge at the same time.
+#[derive(Clone, Copy, Debug, strum::EnumString, strum::VariantNames)]
+#[strum(serialize_all = "snake_case")]
+pub enum ListenClients {
+    /// Only one client at a time, further connections are refused.
+    Single,
+    /// Any number of clients. The first one to send data becomes the writer,
+    /// the others are read-only observers.
+    Multi,
+}
+
+/// Requests from the writing client, destined for the serial port.
+pub enum BridgeEvent {
+    /// Data to send.
+    Data(Bytes),
+    /// A change to the line settings (only used with RFC 2217), along with
+    /// somewhere to say whether it was applied.
+    Line(LineCommand, oneshot::Sender<bool>),
+    /// Something to tell the user about (i.e. a client connecting), which
+    /// is shown along with the output from the port.
+    Message(String),
+}
+
+/// Bridges the serial port to any TCP clients which connect to it.
+pub struct TcpBridge {
+    /// Data read from the serial port which is sent to every client.
+    to_clients: broadcast::Sender<Bytes>,
+    from_clients: mpsc::UnboundedReceiver<BridgeEvent>,
+    shared: Arc<Shared>,
+}
+
+// State shared between the accept loop and each of the client tasks.
+struct Shared {
+    clients: ListenClients,
+    rfc2217: bool,
+    connected: AtomicUsize,
+    next_id: AtomicUsize,
+    writer: Mutex<Option<usize>>,
+    /// The current line settings of the serial port, used to answer RFC 2217 queries.
+    settings: Mutex<LineSettings>,
+}
+
+impl Shared {
+    // Returns true if the client identified by id is allowed to write to the
+    // serial port. The writer role is claimed by the first client to send
+    // data, and held until that client disconnects.
+    fn claim_writer(&self, id: usize) -> bool {
+        let mut writer = self.writer.lock().unwrap();
+        match *writer {
+            Some(writer_id) => writer_id == id,
+            None => {
+                *writer = Some(id);
+                true
+            }
+        }
+    }
+
+    fn release_writer(&self, id: usize) {
+        let mut writer = self.writer.lock().unwrap();
+        if *writer == Some(id) {
+            *writer = None;
+        }
+    }
+}
+
+impl TcpBridge {
+    // Starts listening for TCP connections on addr. If rfc2217 is set then
+    // clients talk telnet and may change the settings of the serial port.
+    pub async fn bind(
+        addr: &str,
+        clients: ListenClients,
+        rfc2217: bool,
+        settings: &LineSettings,
+    ) -> Result<TcpBridge> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| ProgramError::UnableToListen(addr.to_string(), e))?;
+        let (to_clients, _) = broadcast::channel(256);
+        let (client_writer, from_clients) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            clients,
+            rfc2217,
+            connected: AtomicUsize::new(0),
+            next_id: AtomicUsize::new(0),
+            writer: Mutex::new(None),
+            settings: Mutex::new(settings.clone()),
+        });
+
+        tokio::spawn(accept_loop(
+            listener,
+            shared.clone(),
+            to_clients.clone(),
+            client_writer,
+        ));
+
+        Ok(TcpBridge {
+            to_clients,
+            from_clients,
+            shared,
+        })
+    }
+
+    // Sends data which was read from the serial port to all of the clients.
+    pub fn send(&self, data: &[u8]) {
+        // An error just means that no clients are currently connected.
+        let _ = self.to_clients.send(Bytes::copy_from_slice(data));
+    }
+
+    // Waits for a request from the writing client.
+    pub async fn recv(&mut self) -> Option<BridgeEvent> {
+        self.from_clients.recv().await
+    }
+
+    // Records the current line settings of the serial port.
+    pub fn update_settings(&self, settings: &LineSettings) {
+        *self.shared.settings.lock().unwrap() = settings.clone();
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    shared: Arc<Shared>,
+    to_clients: broadcast::Sender<Bytes>,
+    client_writer: mpsc::UnboundedSender<BridgeEvent>,
+) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                let _ =
+                    client_writer.send(BridgeEvent::Message(format!("TCP accept Error: {:?}", e)));
+                continue;
+            }
+        };
+        if let ListenClients::Single = shared.clients {
+            if shared.connected.load(Ordering::SeqCst) > 0 {
+                let _ = stream.write_all(b"serial-monitor: port is busy\r\n").await;
+                continue;
+            }
+        }
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        shared.connected.fetch_add(1, Ordering::SeqCst);
+        let _ = client_writer.send(BridgeEvent::Message(format!(
+            "TCP client {} connected",
+            addr
+        )));
+        tokio::spawn(serve_client(
+            stream,
+            addr,
+            id,
+            shared.clone(),
+            to_clients.subscribe(),
+            client_writer.clone(),
+        ));
+    }
+}
+
+enum ClientEvent {
+    Tcp(std::io::Result<usize>),
+    Serial(std::result::Result<Bytes, broadcast::error::RecvError>),
+}
+
+// Copies data between a single TCP client and the serial port.
+async fn serve_client(
+    stream: TcpStream,
+    addr: SocketAddr,
+    id: usize,
+    shared: Arc<Shared>,
+    mut from_serial: broadcast::Receiver<Bytes>,
+    to_serial: mpsc::UnboundedSender<BridgeEvent>,
+) {
+    let (mut rx_tcp, mut tx_tcp) = stream.into_split();
+    let mut buf = [0u8; 1024];
+    let mut telnet = TelnetClient::new();
+
+    if shared.rfc2217 && tx_tcp.write_all(&telnet.options.offer()).await.is_err() {
+        shared.connected.fetch_sub(1, Ordering::SeqCst);
+        return;
+    }
+
+    loop {
+        let event = {
+            let tcp_event = rx_tcp.read(&mut buf).fuse();
+            let serial_event = from_serial.recv().fuse();
+            pin_mut!(tcp_event, serial_event);
+
+            select! {
+                maybe_tcp = tcp_event => ClientEvent::Tcp(maybe_tcp),
+                maybe_serial = serial_event => ClientEvent::Serial(maybe_serial),
+            }
+        };
+
+        match event {
+            ClientEvent::Tcp(Ok(0)) | ClientEvent::Tcp(Err(_)) => break,
+            ClientEvent::Tcp(Ok(n)) if !shared.rfc2217 => {
+                if forward(&buf[..n], &shared, id, &to_serial).is_none() {
+                    break;
+                }
+            }
+            ClientEvent::Tcp(Ok(n)) => {
+                let replies = match telnet.receive(&buf[..n], &shared, id, &to_serial).await {
+                    Some(replies) => replies,
+                    None => break,
+                };
+                if !replies.is_empty() && tx_tcp.write_all(&replies).await.is_err() {
+                    break;
+                }
+            }
+            ClientEvent::Serial(Ok(data)) => {
+                let result = if shared.rfc2217 {
+                    tx_tcp.write_all(&telnet::escape(&data)).await
+                } else {
+                    tx_tcp.write_all(&data).await
+                };
+                if result.is_err() {
+                    break;
+                }
+            }
+            // A slow client misses some data rather than stalling everybody.
+            ClientEvent::Serial(Err(broadcast::error::RecvError::Lagged(_))) => {}
+            ClientEvent::Serial(Err(broadcast::error::RecvError::Closed)) => break,
+        }
+    }
+
+    shared.release_writer(id);
+    shared.connected.fetch_sub(1, Ordering::SeqCst);
+    let _ = to_serial.send(BridgeEvent::Message(format!(
+        "TCP client {} disconnected",
+        addr
+    )));
+}
+
+// Sends data from a client to the serial port. Data from observers is
+// silently discarded. Returns None if the serial port has gone away.
+fn forward(
+    data: &[u8],
+    shared: &Shared,
+    id: usize,
+    to_serial: &mpsc::UnboundedSender<BridgeEvent>,
+) -> Option<()> {
+    if !data.is_empty() && shared.claim_writer(id) {
+        to_serial
+            .send(BridgeEvent::Data(Bytes::copy_from_slice(data)))
+            .ok()?;
+    }
+    Some(())
+}
+
+// The telnet side of a connection with an RFC 2217 client.
+struct TelnetClient {
+    parser: TelnetParser,
+    options: TelnetOptions,
+    /// True if the last data byte received was a CR.
+    last_was_cr: bool,
+}
+
+impl TelnetClient {
+    fn new() -> TelnetClient {
+        TelnetClient {
+            parser: TelnetParser::new(),
+            options: TelnetOptions::new(
+                &[OPT_BINARY, OPT_SGA, OPT_ECHO],
+                &[OPT_BINARY, OPT_SGA, OPT_COM_PORT],
+            ),
+            last_was_cr: false,
+        }
+    }
+
+    // Handles data received from the client, passing the data and any
+    // changes to the line settings on to the serial port in the order they
+    // arrived. Returns the replies to send to the client, or None if the
+    // serial port has gone away.
+    async fn receive(
+        &mut self,
+        received: &[u8],
+        shared: &Shared,
+        id: usize,
+        to_serial: &mpsc::UnboundedSender<BridgeEvent>,
+    ) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut replies = Vec::new();
+
+        for byte in received.iter() {
+            match self.parser.decode_byte(*byte) {
+                Decoded::Data(byte) => {
+                    // Outside of binary mode a bare CR is sent as CR NUL.
+                    let padding =
+                        self.last_was_cr && byte == 0 && !self.options.remote_enabled(OPT_BINARY);
+                    self.last_was_cr = byte == b'\r';
+                    if !padding {
+                        data.push(byte);
+                    }
+                }
+                Decoded::Command(event) => {
+                    // The data which came before the command is sent first.
+                    forward(&data, shared, id, to_serial)?;
+                    data.clear();
+                    self.handle_command(&event, shared, id, to_serial, &mut replies)
+                        .await?;
+                }
+                Decoded::Nothing => {}
+            }
+        }
+        forward(&data, shared, id, to_serial)?;
+        Some(replies)
+    }
+
+    // Handles a telnet command received from the client, applying any change
+    // to the line settings, and adding the reply (if any) to replies. Returns
+    // None if the serial port has gone away.
+    async fn handle_command(
+        &mut self,
+        event: &TelnetEvent,
+        shared: &Shared,
+        id: usize,
+        to_serial: &mpsc::UnboundedSender<BridgeEvent>,
+        replies: &mut Vec<u8>,
+    ) -> Option<()> {
+        match event {
+            TelnetEvent::Subnegotiation(OPT_COM_PORT, payload) => {
+                // Requests from observers are answered, but not applied.
+                let request = rfc2217::server_request(payload).filter(|_| shared.claim_writer(id));
+                if let Some(cmd) = request {
+                    // Wait for the change to be made, so that the reply
+                    // shows whether it worked.
+                    let (applied, result) = oneshot::channel();
+                    to_serial.send(BridgeEvent::Line(cmd, applied)).ok()?;
+                    result.await.ok()?;
+                }
+                let settings = shared.settings.lock().unwrap().clone();
+                if let Some(reply) = rfc2217::server_reply(payload, &settings) {
+                    replies.extend_from_slice(&reply);
+                }
+            }
+            TelnetEvent::Subnegotiation(_, _) => {}
+            _ => {
+                if let Some(reply) = self.options.negotiate(event) {
+                    replies.extend_from_slice(&reply);
+                }
+            }
+        }
+        Some(())
+    }
+}
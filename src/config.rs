@@ -0,0 +1,382 @@
+use crate::error::{ProgramError, Result};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use structopt::clap::{App, AppSettings, ArgMatches, ErrorKind};
+
+// Name of the project-local configuration file, which is looked for in the
+// current directory and each of its parents.
+const PROJECT_CONFIG: &str = ".serial-monitor.toml";
+
+// Tables which may appear at the top level as well as in a profile, and which
+// hold settings rather than command line options.
+const SECTIONS: &[&str] = &["macros", "keys"];
+
+/// Settings read from the configuration files.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The files which were read, in the order that they were applied.
+    pub files: Vec<PathBuf>,
+    /// Named profiles, each holding values for command line options.
+    pub profiles: BTreeMap<String, toml::Table>,
+    /// The top level tables listed in SECTIONS.
+    sections: BTreeMap<String, toml::Table>,
+    /// Files which couldn't be used, along with the reason. These are only
+    /// reported when the configuration is needed, so that a mistake doesn't
+    /// stop --list (for instance) from working.
+    problems: Vec<(String, String)>,
+}
+
+// Returns the path of the per-user configuration file.
+fn user_config() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::home_dir()?.join(".config"),
+    };
+    Some(config_dir.join("serial-monitor").join("config.toml"))
+}
+
+// Returns the path of the closest project-local configuration file.
+fn project_config() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+impl Config {
+    // Reads the per-user configuration file followed by the project-local
+    // one. Values from the project-local file override those from the
+    // per-user file. Missing files are skipped.
+    pub fn load() -> Config {
+        let mut config = Config::default();
+        for path in [user_config(), project_config()].iter().flatten() {
+            if path.is_file() {
+                if let Err(reason) = config.merge_file(path) {
+                    config.problems.push((path.display().to_string(), reason));
+                }
+            }
+        }
+        config
+    }
+
+    // Returns an error for the first configuration file which couldn't be used.
+    fn check(&self) -> Result<()> {
+        match self.problems.first() {
+            Some((file, reason)) => Err(ProgramError::ConfigError(file.clone(), reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn merge_file(&mut self, path: &Path) -> std::result::Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        self.merge_str(&text)?;
+        self.files.push(path.to_path_buf());
+        Ok(())
+    }
+
+    // Merges the contents of a configuration file, overriding values which
+    // were read from earlier files.
+    fn merge_str(&mut self, text: &str) -> std::result::Result<(), String> {
+        let mut table: toml::Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        if let Some(profiles) = table.remove("profiles") {
+            let profiles = match profiles {
+                toml::Value::Table(profiles) => profiles,
+                _ => return Err("profiles must be a table".to_string()),
+            };
+            for (name, profile) in profiles {
+                let profile = match profile {
+                    toml::Value::Table(profile) => profile,
+                    _ => return Err(format!("profile '{}' must be a table", name)),
+                };
+                self.profiles.entry(name).or_default().extend(profile);
+            }
+        }
+        for name in SECTIONS {
+            match table.remove(*name) {
+                Some(toml::Value::Table(section)) => self
+                    .sections
+                    .entry(name.to_string())
+                    .or_default()
+                    .extend(section),
+                Some(_) => return Err(format!("{} must be a table", name)),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the profile with the given name.
+    pub fn profile(&self, name: &str) -> Result<&toml::Table> {
+        self.check()?;
+        self.profiles.get(name).ok_or_else(|| {
+            let files: Vec<String> = self.files.iter().map(|f| f.display().to_string()).collect();
+            ProgramError::ConfigError(
+                if files.is_empty() {
+                    "(no configuration files found)".to_string()
+                } else {
+                    files.join(", ")
+                },
+                format!("no profile named '{}'", name),
+            )
+        })
+    }
+
+    // Returns one of the SECTIONS tables, with the values from the profile's
+    // table of the same name (if any) overriding the top level ones.
+    pub fn section(&self, name: &str, profile: Option<&str>) -> Result<toml::Table> {
+        self.check()?;
+        let mut table = self.sections.get(name).cloned().unwrap_or_default();
+        if let Some(profile) = profile {
+            match self.profile(profile)?.get(name) {
+                Some(toml::Value::Table(values)) => table.extend(values.clone()),
+                Some(_) => {
+                    return Err(ProgramError::ConfigError(
+                        format!("profile '{}'", profile),
+                        format!("{} must be a table", name),
+                    ))
+                }
+                None => {}
+            }
+        }
+        Ok(table)
+    }
+}
+
+// Returns the command line argument for the option named by a key in a
+// profile. Keys are the long option names, i.e. listen-clients (or
+// listen_clients), and options which only have a short form are mapped here.
+fn option_arg(option: &str) -> String {
+    match option {
+        "ctrl-y-exit" => "-y".to_string(),
+        option => format!("--{}", option),
+    }
+}
+
+// Returns whether the option is a flag (Some(true)) or takes a value
+// (Some(false)), or None if the app has no such option. This is worked out by
+// parsing the option's argument on its own: options with a default value
+// accept being given without one, but then have a value, unlike flags.
+fn is_flag(app: &App, option: &str) -> Option<bool> {
+    let app = app.clone().setting(AppSettings::ColorNever);
+    match app.get_matches_from_safe(["serial-monitor", &option_arg(option)]) {
+        Ok(matches) => Some(matches.value_of(option).is_none()),
+        Err(e) => match e.kind {
+            ErrorKind::UnknownArgument | ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => {
+                None
+            }
+            ErrorKind::EmptyValue => Some(false),
+            // Other errors (like a missing required option) mean that the
+            // flag itself was accepted.
+            _ => Some(true),
+        },
+    }
+}
+
+// Converts the values in a profile into command line arguments for app.
+// Options which were given on the command line (as recorded in matches), or
+// named with --unset, are skipped, so that they override the profile.
+pub fn profile_args(
+    name: &str,
+    profile: &toml::Table,
+    unset: &[String],
+    app: &App,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>> {
+    let error = |reason: String| ProgramError::ConfigError(format!("profile '{}'", name), reason);
+    let unset: Vec<String> = unset
+        .iter()
+        .map(|option| option.replace('_', "-"))
+        .collect();
+    for option in &unset {
+        if option != "url" && is_flag(app, option).is_none() {
+            return Err(ProgramError::IncompatibleOptions(format!(
+                "--unset {}: there's no option named '{}'",
+                option, option
+            )));
+        }
+    }
+    let mut args = Vec::new();
+    for (key, value) in profile {
+        let option = key.replace('_', "-");
+        if option == "profile" || SECTIONS.contains(&key.as_str()) {
+            continue;
+        }
+        // The URL is the positional argument, so it's given without an
+        // option name.
+        let (arg, arg_name, flag) = if option == "url" {
+            (None, "URL", false)
+        } else {
+            let flag = is_flag(app, &option)
+                .ok_or_else(|| error(format!("there's no option named '{}'", key)))?;
+            (Some(option_arg(&option)), option.as_str(), flag)
+        };
+        if unset.contains(&option) || matches.occurrences_of(arg_name) > 0 {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match (value, flag) {
+                (toml::Value::Boolean(true), true) => {
+                    args.extend(arg.clone().map(OsString::from));
+                    continue;
+                }
+                (toml::Value::Boolean(false), true) => continue,
+                (_, true) => {
+                    return Err(error(format!(
+                        "'{}' is a flag, so it must be true or false",
+                        key
+                    )))
+                }
+                (toml::Value::String(s), false) => s.clone(),
+                (toml::Value::Integer(n), false) => n.to_string(),
+                (toml::Value::Float(n), false) => n.to_string(),
+                _ => return Err(error(format!("unsupported value for '{}'", key))),
+            };
+            args.extend(arg.clone().map(OsString::from));
+            args.push(OsString::from(value));
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opt;
+    use structopt::StructOpt;
+
+    fn profile(text: &str) -> toml::Table {
+        text.parse().unwrap()
+    }
+
+    fn args(profile_text: &str, command_line: &[&str]) -> Result<Vec<String>> {
+        let command_line: Vec<&str> = ["serial-monitor"]
+            .iter()
+            .chain(command_line)
+            .copied()
+            .collect();
+        let matches = Opt::clap().get_matches_from(&command_line);
+        let opt = Opt::from_clap(&matches);
+        let args = profile_args(
+            "test",
+            &profile(profile_text),
+            &opt.unset,
+            &Opt::clap(),
+            &matches,
+        )?;
+        Ok(args
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect())
+    }
+
+    // Parses the command line with the profile's values filled in, as
+    // --profile does.
+    fn merged(profile_text: &str, command_line: &[&str]) -> Opt {
+        let mut merged = vec!["serial-monitor".to_string()];
+        merged.extend(command_line.iter().map(|arg| arg.to_string()));
+        merged.extend(args(profile_text, command_line).unwrap());
+        Opt::from_clap(&Opt::clap().get_matches_from(merged))
+    }
+
+    fn error(profile_text: &str, command_line: &[&str]) -> String {
+        args(profile_text, command_line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn profile_values() {
+        assert_eq!(args("baud = 9600", &[]).unwrap(), ["--baud", "9600"]);
+        assert_eq!(
+            args("listen_clients = 2", &[]).unwrap(),
+            ["--listen-clients", "2"]
+        );
+        assert_eq!(args("echo = true", &[]).unwrap(), ["--echo"]);
+        assert_eq!(args("echo = false", &[]).unwrap(), Vec::<String>::new());
+        assert_eq!(args("ctrl_y_exit = true", &[]).unwrap(), ["-y"]);
+        assert_eq!(
+            args("index = [1, 2]", &[]).unwrap(),
+            ["--index", "1", "--index", "2"]
+        );
+        assert_eq!(
+            args("url = 'tcp://host:23'", &[]).unwrap(),
+            ["tcp://host:23"]
+        );
+        assert_eq!(
+            args("[macros]\nF5 = 'x'", &[]).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn profile_errors() {
+        assert_eq!(
+            error("bauds = 9600", &[]),
+            "profile 'test': there's no option named 'bauds'"
+        );
+        assert_eq!(
+            error("help = true", &[]),
+            "profile 'test': there's no option named 'help'"
+        );
+        assert_eq!(
+            error("echo = 'yes'", &[]),
+            "profile 'test': 'echo' is a flag, so it must be true or false"
+        );
+        assert_eq!(
+            error("baud = true", &[]),
+            "profile 'test': unsupported value for 'baud'"
+        );
+        assert_eq!(
+            error("baud = 9600", &["--unset", "bauds"]),
+            "--unset bauds: there's no option named 'bauds'"
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_profile() {
+        let profile_text = "baud = 9600\necho = true\nurl = 'tcp://host:23'";
+        let opt = merged(profile_text, &[]);
+        assert_eq!((opt.baud, opt.echo), (9600, true));
+        assert_eq!(opt.url.as_deref(), Some("tcp://host:23"));
+
+        let opt = merged(profile_text, &["--baud", "115200", "tcp://other:23"]);
+        assert_eq!((opt.baud, opt.echo), (115200, true));
+        assert_eq!(opt.url.as_deref(), Some("tcp://other:23"));
+
+        let opt = merged(profile_text, &["--unset", "echo", "--unset", "url"]);
+        assert_eq!((opt.baud, opt.echo), (9600, false));
+        assert_eq!(opt.url, None);
+
+        // Short options count as being given on the command line too.
+        assert_eq!(merged(profile_text, &["-b", "300"]).baud, 300);
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones() {
+        let mut config = Config::default();
+        config
+            .merge_str("[profiles.pico]\nbaud = 9600\necho = true\n[macros]\nF5 = 'a'\nF6 = 'b'")
+            .unwrap();
+        config
+            .merge_str("[profiles.pico]\nbaud = 921600\n[profiles.pico.macros]\nF6 = 'c'\n[macros]\nF5 = 'd'")
+            .unwrap();
+        let pico = config.profile("pico").unwrap();
+        assert_eq!(pico.get("baud"), Some(&toml::Value::Integer(921600)));
+        assert_eq!(pico.get("echo"), Some(&toml::Value::Boolean(true)));
+
+        let macros = config.section("macros", Some("pico")).unwrap();
+        assert_eq!(macros.get("F5"), Some(&toml::Value::from("d")));
+        assert_eq!(macros.get("F6"), Some(&toml::Value::from("c")));
+        let macros = config.section("macros", None).unwrap();
+        assert_eq!(macros.get("F6"), Some(&toml::Value::from("b")));
+
+        assert!(config.profile("other").is_err());
+        assert_eq!(
+            config.merge_str("profiles = 1").unwrap_err(),
+            "profiles must be a table"
+        );
+    }
+}
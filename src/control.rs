@@ -0,0 +1,332 @@
+use crate::error::{ProgramError, Result};
+use crate::transport::LineCommand;
+use std::result::Result as StdResult;
+use tokio::sync::{mpsc, oneshot};
+
+/// Commands accepted on the control socket.
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    /// Send bytes to the port.
+    Send(Vec<u8>),
+    /// Change the line settings of the port.
+    Line(LineCommand),
+    ToggleDtr,
+    ToggleRts,
+    /// Start logging received data to a file.
+    LogStart(String),
+    LogStop,
+    /// Report the state of the session.
+    Status,
+    /// Close the port so that another program can use it.
+    Release,
+    /// Reopen a port which was released.
+    Reacquire,
+}
+
+/// A command along with somewhere to send the reply.
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: oneshot::Sender<StdResult<String, String>>,
+}
+
+/// Accepts line oriented commands on a unix domain socket, so that other
+/// programs can steer a running session.
+pub struct ControlServer {
+    path: String,
+    requests: mpsc::UnboundedReceiver<ControlRequest>,
+}
+
+const HELP: &str = "\
+send <text>        Send text (\\r \\n \\t \\e \\\\ and \\xHH escapes are allowed)
+hex <bytes>        Send bytes written in hex (i.e. hex 03 04)
+baud <rate>        Change the baud rate
+dtr on|off|toggle  Change the DTR line
+rts on|off|toggle  Change the RTS line
+break on|off       Change the break condition
+log <file>         Start logging received data to a file
+log stop           Stop logging
+status             Report the state of the session
+release            Close the port so that another program can use it
+reacquire          Reopen the port after a release
+";
+
+impl ControlServer {
+    // Starts listening for connections on the unix domain socket at path.
+    #[cfg(unix)]
+    pub fn bind(path: &str) -> Result<ControlServer> {
+        use std::os::unix::fs::FileTypeExt;
+
+        // Remove a socket left behind by a previous session, but leave any
+        // other kind of file alone. A socket which still accepts connections
+        // belongs to a session which is running.
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(ProgramError::UnableToListen(
+                        path.to_string(),
+                        std::io::Error::new(
+                            std::io::ErrorKind::AddrInUse,
+                            "already in use by another session",
+                        ),
+                    ));
+                }
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .map_err(|e| ProgramError::UnableToListen(path.to_string(), e))?;
+        let (request_writer, requests) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_client(stream, request_writer.clone()));
+                    }
+                    Err(e) => println!("Control socket accept Error: {:?}\r", e),
+                }
+            }
+        });
+
+        Ok(ControlServer {
+            path: path.to_string(),
+            requests,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(path: &str) -> Result<ControlServer> {
+        Err(ProgramError::UnableToListen(
+            path.to_string(),
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "control sockets are only supported on unix",
+            ),
+        ))
+    }
+
+    // Waits for a command from a client.
+    pub async fn recv(&mut self) -> Option<ControlRequest> {
+        self.requests.recv().await
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Reads commands from a single client, one per line, and writes back the
+// replies. Each reply ends with a line containing "ok" or "error: <reason>".
+#[cfg(unix)]
+async fn serve_client(
+    stream: tokio::net::UnixStream,
+    requests: mpsc::UnboundedSender<ControlRequest>,
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (rx, mut tx) = stream.into_split();
+    let mut lines = BufReader::new(rx).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let result = if line == "help" {
+            Ok(HELP.to_string())
+        } else {
+            match parse_command(line) {
+                Ok(command) => {
+                    let (reply, response) = oneshot::channel();
+                    if requests.send(ControlRequest { command, reply }).is_err() {
+                        break;
+                    }
+                    response
+                        .await
+                        .unwrap_or_else(|_| Err("session ended".to_string()))
+                }
+                Err(e) => Err(e),
+            }
+        };
+        let reply = match result {
+            Ok(text) => format!("{}ok\n", text),
+            Err(e) => format!("error: {}\n", e),
+        };
+        if tx.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn parse_on_off(arg: &str) -> StdResult<bool, String> {
+    match arg {
+        "on" | "1" => Ok(true),
+        "off" | "0" => Ok(false),
+        _ => Err(format!("expecting on or off, found '{}'", arg)),
+    }
+}
+
+// Converts a line received on the control socket into a command.
+fn parse_command(line: &str) -> StdResult<ControlCommand, String> {
+    let (command, arg) = match line.find(' ') {
+        Some(idx) => (&line[..idx], line[idx + 1..].trim_start()),
+        None => (line, ""),
+    };
+    match command {
+        "send" => Ok(ControlCommand::Send(unescape(arg)?)),
+        "hex" => Ok(ControlCommand::Send(parse_hex(arg)?)),
+        "baud" => match arg.parse::<u32>() {
+            Ok(baud) if baud > 0 => Ok(ControlCommand::Line(LineCommand::Baud(baud))),
+            _ => Err(format!("invalid baud rate '{}'", arg)),
+        },
+        "dtr" if arg == "toggle" => Ok(ControlCommand::ToggleDtr),
+        "dtr" => Ok(ControlCommand::Line(LineCommand::Dtr(parse_on_off(arg)?))),
+        "rts" if arg == "toggle" => Ok(ControlCommand::ToggleRts),
+        "rts" => Ok(ControlCommand::Line(LineCommand::Rts(parse_on_off(arg)?))),
+        "break" => Ok(ControlCommand::Line(LineCommand::Break(parse_on_off(arg)?))),
+        "log" if arg == "stop" => Ok(ControlCommand::LogStop),
+        "log" if !arg.is_empty() => Ok(ControlCommand::LogStart(arg.to_string())),
+        "status" => Ok(ControlCommand::Status),
+        "release" => Ok(ControlCommand::Release),
+        "reacquire" => Ok(ControlCommand::Reacquire),
+        _ => Err(format!("unrecognized command '{}' (try help)", line)),
+    }
+}
+
+// Converts a string of hex digits (optionally separated by whitespace) into bytes.
+fn parse_hex(arg: &str) -> StdResult<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for word in arg.split_whitespace() {
+        if word.len() % 2 != 0 {
+            return Err(format!("'{}' has an odd number of hex digits", word));
+        }
+        if !word.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return Err(format!("invalid hex '{}'", word));
+        }
+        for i in (0..word.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&word[i..i + 2], 16).unwrap());
+        }
+    }
+    if bytes.is_empty() {
+        return Err("no bytes to send".to_string());
+    }
+    Ok(bytes)
+}
+
+// Converts a string containing backslash escapes into bytes.
+pub fn unescape(text: &str) -> StdResult<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('e') => bytes.push(0x1b),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                if digits.len() != 2 || !digits.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                    return Err(format!("invalid escape '\\x{}'", digits));
+                }
+                bytes.push(u8::from_str_radix(&digits, 16).unwrap());
+            }
+            Some(other) => return Err(format!("invalid escape '\\{}'", other)),
+            None => return Err("trailing backslash".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let commands = [
+            ("send hi\\r", ControlCommand::Send(b"hi\r".to_vec())),
+            (
+                "send  two  spaces",
+                ControlCommand::Send(b"two  spaces".to_vec()),
+            ),
+            ("hex 03 04", ControlCommand::Send(vec![3, 4])),
+            ("baud 9600", ControlCommand::Line(LineCommand::Baud(9600))),
+            ("dtr on", ControlCommand::Line(LineCommand::Dtr(true))),
+            ("dtr 0", ControlCommand::Line(LineCommand::Dtr(false))),
+            ("dtr toggle", ControlCommand::ToggleDtr),
+            ("rts off", ControlCommand::Line(LineCommand::Rts(false))),
+            ("rts toggle", ControlCommand::ToggleRts),
+            ("break 1", ControlCommand::Line(LineCommand::Break(true))),
+            (
+                "log /tmp/out.log",
+                ControlCommand::LogStart("/tmp/out.log".to_string()),
+            ),
+            ("log stop", ControlCommand::LogStop),
+            ("status", ControlCommand::Status),
+            ("release", ControlCommand::Release),
+            ("reacquire", ControlCommand::Reacquire),
+        ];
+        for (line, command) in commands {
+            assert_eq!(parse_command(line), Ok(command), "{}", line);
+        }
+    }
+
+    #[test]
+    fn command_errors() {
+        let errors = [
+            ("baud", "invalid baud rate ''"),
+            ("baud 0", "invalid baud rate '0'"),
+            ("baud fast", "invalid baud rate 'fast'"),
+            ("dtr maybe", "expecting on or off, found 'maybe'"),
+            ("break toggle", "expecting on or off, found 'toggle'"),
+            ("log", "unrecognized command 'log' (try help)"),
+            ("hex", "no bytes to send"),
+            ("send \\q", "invalid escape '\\q'"),
+            ("reboot now", "unrecognized command 'reboot now' (try help)"),
+            ("", "unrecognized command '' (try help)"),
+        ];
+        for (line, error) in errors {
+            assert_eq!(parse_command(line), Err(error.to_string()), "{}", line);
+        }
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex("03 04"), Ok(vec![3, 4]));
+        assert_eq!(parse_hex("0304ff"), Ok(vec![3, 4, 0xff]));
+        assert_eq!(parse_hex(" 0a\tFF "), Ok(vec![0x0a, 0xff]));
+        assert_eq!(
+            parse_hex("3"),
+            Err("'3' has an odd number of hex digits".to_string())
+        );
+        assert_eq!(parse_hex("0g"), Err("invalid hex '0g'".to_string()));
+        assert_eq!(parse_hex("+1"), Err("invalid hex '+1'".to_string()));
+        assert_eq!(parse_hex("\u{e9}"), Err("invalid hex '\u{e9}'".to_string()));
+        assert_eq!(parse_hex(" "), Err("no bytes to send".to_string()));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape("plain"), Ok(b"plain".to_vec()));
+        assert_eq!(
+            unescape("\\r\\n\\t\\e\\0\\\\"),
+            Ok(b"\r\n\t\x1b\0\\".to_vec())
+        );
+        assert_eq!(unescape("\\x03\\xFFa"), Ok(vec![3, 0xff, b'a']));
+        assert_eq!(unescape("\u{e9}"), Ok("\u{e9}".as_bytes().to_vec()));
+        assert_eq!(unescape("\\x3"), Err("invalid escape '\\x3'".to_string()));
+        assert_eq!(unescape("\\x+1"), Err("invalid escape '\\x+1'".to_string()));
+        assert_eq!(unescape("\\xg0"), Err("invalid escape '\\xg0'".to_string()));
+        assert_eq!(unescape("\\q"), Err("invalid escape '\\q'".to_string()));
+        assert_eq!(unescape("end\\"), Err("trailing backslash".to_string()));
+    }
+}
@@ -2,7 +2,16 @@ use std::fmt;
 
 pub enum ProgramError {
     NoPortFound,
+    NoPortSelected,
     UnableToOpen(String, std::io::Error),
+    UnableToListen(String, std::io::Error),
+    UnableToCreateLog(String, std::io::Error),
+    UnsupportedUrl(String),
+    LineSettingsNotSupported,
+    PortReleased,
+    IncompatibleOptions(String),
+    ConfigError(String, String),
+    InvalidFilter(String, String),
     IoError(std::io::Error),
     SerialPortError(mio_serial::Error),
 }
@@ -27,9 +36,32 @@ impl fmt::Debug for ProgramError {
             ProgramError::NoPortFound => {
                 write!(f, "No USB serial adapter found which matches criteria.")
             }
+            ProgramError::NoPortSelected => write!(f, "No port was selected"),
             ProgramError::UnableToOpen(port_name, err) => {
                 write!(f, "Unable to open serial port '{}': {}", port_name, err)
             }
+            ProgramError::UnableToListen(addr, err) => {
+                write!(f, "Unable to listen on '{}': {}", addr, err)
+            }
+            ProgramError::UnableToCreateLog(path, err) => {
+                write!(f, "Unable to open log file '{}': {}", path, err)
+            }
+            ProgramError::UnsupportedUrl(url) => {
+                write!(
+                    f,
+                    "Unsupported URL '{}' (expecting rfc2217://, tcp:// or telnet://)",
+                    url
+                )
+            }
+            ProgramError::LineSettingsNotSupported => {
+                write!(f, "Line settings can't be changed on this connection")
+            }
+            ProgramError::PortReleased => write!(f, "The port has been released"),
+            ProgramError::IncompatibleOptions(reason) => write!(f, "{}", reason),
+            ProgramError::ConfigError(source, reason) => write!(f, "{}: {}", source, reason),
+            ProgramError::InvalidFilter(pattern, reason) => {
+                write!(f, "Invalid filter '{}': {}", pattern, reason)
+            }
             ProgramError::IoError(err) => write!(f, "{}", err),
             ProgramError::SerialPortError(err) => write!(f, "SerialPortError: {}", err),
         }
@@ -0,0 +1,114 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// The number of lines kept, both in memory and in the history file.
+const MAX_LINES: usize = 1000;
+
+/// Lines which have been sent, oldest first, remembered across sessions in a
+/// history file.
+pub struct History {
+    lines: Vec<String>,
+    path: Option<PathBuf>,
+    /// The number of lines in the history file.
+    file_lines: usize,
+}
+
+// Returns the path of the history file with the given name. Characters which
+// don't belong in file names are replaced.
+fn history_path(name: &str) -> Option<PathBuf> {
+    let name: String = name
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' || ch == '.' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Some(
+        dirs::data_local_dir()?
+            .join("serial-monitor")
+            .join("history")
+            .join(name),
+    )
+}
+
+// Opens the history file for writing, creating it if needed. Since the
+// history may hold anything which was typed, only the user can read it.
+fn open_history_file(path: &Path, append: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).write(true);
+    if append {
+        options.append(true);
+    } else {
+        options.truncate(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+impl History {
+    // Reads the history file with the given name. A missing (or unreadable)
+    // file just means that there's no history yet.
+    pub fn load(name: &str) -> History {
+        let path = history_path(name);
+        let mut lines: Vec<String> = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        let file_lines = lines.len();
+        if lines.len() > MAX_LINES {
+            lines.drain(..lines.len() - MAX_LINES);
+        }
+        History {
+            lines,
+            path,
+            file_lines,
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    // Adds a line to the history, and appends it to the history file. Once
+    // the file holds more than MAX_LINES, it's rewritten with just the lines
+    // kept in memory. Empty lines, and repeats of the previous line, aren't
+    // added.
+    pub fn push(&mut self, line: &str) {
+        if line.trim().is_empty() || self.lines.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.lines.push(line.to_string());
+        if self.lines.len() > MAX_LINES {
+            self.lines.remove(0);
+        }
+        if let Some(path) = &self.path {
+            // Failing to save the history isn't worth interrupting the session for.
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            if self.file_lines >= MAX_LINES {
+                let mut text = self.lines.join("\n");
+                text.push('\n');
+                if let Ok(mut file) = open_history_file(path, false) {
+                    if file.write_all(text.as_bytes()).is_ok() {
+                        self.file_lines = self.lines.len();
+                    }
+                }
+            } else if let Ok(mut file) = open_history_file(path, true) {
+                if writeln!(file, "{}", line).is_ok() {
+                    self.file_lines += 1;
+                }
+            }
+        }
+    }
+}
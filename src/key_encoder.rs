@@ -0,0 +1,480 @@
+use crate::config::Config;
+use crate::control::unescape;
+use crate::error::{ProgramError, Result};
+use crate::keys::KeySpec;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+
+// The escape sequences are the ones used by xterm (which VT220 style
+// terminal programs, and the Linux console, mostly agree with). With the
+// xterm keymap Backspace sends 0x7f.
+//
+//  Up      ESC [A          F1   ESC OP     F7   ESC [18~
+//  Down    ESC [B          F2   ESC OQ     F8   ESC [19~
+//  Right   ESC [C          F3   ESC OR     F9   ESC [20~
+//  Left    ESC [D          F4   ESC OS     F10  ESC [21~
+//  Home    ESC [H          F5   ESC [15~   F11  ESC [23~
+//  End     ESC [F          F6   ESC [17~   F12  ESC [24~
+//  Insert  ESC [2~         PageUp    ESC [5~
+//  Del     ESC [3~         PageDown  ESC [6~
+//
+// Keys pressed with Shift, Alt or Control add a modifier parameter, i.e.
+// Control-Up is ESC [1;5A and Shift-F5 is ESC [15;2~. Alt with any other key
+// sends ESC followed by what the key would normally send.
+//
+// The other keymaps differ from this as follows:
+//
+//  vt100   Backspace sends 0x08, and modifiers aren't sent.
+//  vt220   Backspace sends 0x7f, Home is ESC [1~, End is ESC [4~, F1-F4 are
+//          ESC [11~ thru ESC [14~, and modifiers aren't sent.
+//  linux   Backspace sends 0x7f, Home is ESC [1~, End is ESC [4~, F1-F5 are
+//          ESC [[A thru ESC [[E, and modifiers aren't sent.
+//
+// Without a keymap, keys are sent the way xterm sends them except that
+// Backspace sends 0x08 (which is what MicroPython expects).
+
+/// The terminal whose key encoding is used.
+#[derive(Clone, Copy, Debug, PartialEq, strum::EnumString, strum::VariantNames)]
+#[strum(serialize_all = "snake_case")]
+pub enum Keymap {
+    Vt100,
+    Vt220,
+    Xterm,
+    /// The Linux console.
+    Linux,
+}
+
+// The sequences sent by F1-F5 on the Linux console.
+const LINUX_FUNCTION_KEYS: [&str; 5] = ["\x1b[[A", "\x1b[[B", "\x1b[[C", "\x1b[[D", "\x1b[[E"];
+
+// The forms of escape sequence sent by special keys.
+enum Sequence {
+    /// ESC [ followed by a character (i.e. ESC [A for Up).
+    Csi(char),
+    /// ESC O followed by a character (i.e. ESC OP for F1).
+    Ss3(char),
+    /// ESC [ followed by a number and ~ (i.e. ESC [3~ for Delete).
+    Tilde(u8),
+    /// A sequence which modifiers can't be added to.
+    Fixed(&'static str),
+}
+
+// Returns the escape sequence sent by a special key, or None if it isn't one.
+fn sequence(code: KeyCode, keymap: Option<Keymap>) -> Option<Sequence> {
+    let vt220_style = matches!(keymap, Some(Keymap::Vt220) | Some(Keymap::Linux));
+    Some(match code {
+        KeyCode::Up => Sequence::Csi('A'),
+        KeyCode::Down => Sequence::Csi('B'),
+        KeyCode::Right => Sequence::Csi('C'),
+        KeyCode::Left => Sequence::Csi('D'),
+        KeyCode::Home if vt220_style => Sequence::Tilde(1),
+        KeyCode::End if vt220_style => Sequence::Tilde(4),
+        KeyCode::Home => Sequence::Csi('H'),
+        KeyCode::End => Sequence::Csi('F'),
+        KeyCode::Insert => Sequence::Tilde(2),
+        KeyCode::Delete => Sequence::Tilde(3),
+        KeyCode::PageUp => Sequence::Tilde(5),
+        KeyCode::PageDown => Sequence::Tilde(6),
+        KeyCode::F(n @ 1..=5) if keymap == Some(Keymap::Linux) => {
+            Sequence::Fixed(LINUX_FUNCTION_KEYS[n as usize - 1])
+        }
+        KeyCode::F(n @ 1..=4) if keymap == Some(Keymap::Vt220) => Sequence::Tilde(n + 10),
+        KeyCode::F(n @ 1..=4) => Sequence::Ss3((b'P' + n - 1) as char),
+        KeyCode::F(5) => Sequence::Tilde(15),
+        KeyCode::F(n @ 6..=10) => Sequence::Tilde(n + 11),
+        KeyCode::F(n @ 11..=12) => Sequence::Tilde(n + 12),
+        _ => return None,
+    })
+}
+
+// Returns the xterm modifier parameter (1 + Shift 1, Alt 2, Control 4), or
+// None if no modifiers are held down.
+fn modifier_param(modifiers: KeyModifiers) -> Option<u8> {
+    let mut param = 1;
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        param += 1;
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        param += 2;
+    }
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        param += 4;
+    }
+    (param > 1).then_some(param)
+}
+
+// Returns whether a key is a character typed using AltGr. Windows reports
+// AltGr as Control and Alt, which is how characters like @ and { are typed on
+// many keyboards.
+pub fn is_altgr_char(key_event: &KeyEvent) -> bool {
+    match key_event.code {
+        KeyCode::Char(ch) => {
+            key_event
+                .modifiers
+                .contains(KeyModifiers::CONTROL | KeyModifiers::ALT)
+                && !ch.is_ascii_alphabetic()
+        }
+        _ => false,
+    }
+}
+
+// Returns the byte sent for a character typed with Control held down.
+pub fn control_char(ch: char) -> Option<u8> {
+    match ch {
+        'a'..='z' | 'A'..='Z' => Some(ch as u8 & 0x1f),
+        '@' | ' ' | '2' => Some(0x00),
+        '[' | '3' => Some(0x1b),
+        // crossterm returns Control-4 thru 7 for \x1c thru \x1f
+        '\\' | '4' => Some(0x1c),
+        ']' | '5' => Some(0x1d),
+        '^' | '6' => Some(0x1e),
+        '_' | '7' | '/' => Some(0x1f),
+        '8' | '?' => Some(0x7f),
+        _ => None,
+    }
+}
+
+/// Turns keys into the bytes sent to the port, using a keymap along with
+/// overrides for individual keys from the [keys] table of the configuration
+/// file.
+#[derive(Debug, Default)]
+pub struct KeyEncoder {
+    keymap: Option<Keymap>,
+    overrides: Vec<(KeySpec, Vec<u8>)>,
+}
+
+impl KeyEncoder {
+    // Creates an encoder for the keymap, with the overrides from the [keys]
+    // table (and the profile's keys table, which overrides it).
+    pub fn load(
+        keymap: Option<Keymap>,
+        config: &Config,
+        profile: Option<&str>,
+    ) -> Result<KeyEncoder> {
+        let mut overrides = Vec::new();
+        for (key, value) in &config.section("keys", profile)? {
+            let error =
+                |reason: String| ProgramError::ConfigError(format!("key '{}'", key), reason);
+            let spec: KeySpec = key.parse().map_err(error)?;
+            let data = match value {
+                toml::Value::String(text) => unescape(text).map_err(error)?,
+                _ => return Err(error("expecting a string".to_string())),
+            };
+            overrides.push((spec, data));
+        }
+        Ok(KeyEncoder { keymap, overrides })
+    }
+
+    // Returns whether the [keys] table changes what a key sends.
+    pub fn overrides(&self, key_event: &KeyEvent) -> bool {
+        self.overrides
+            .iter()
+            .any(|(spec, _)| spec.matches(key_event))
+    }
+
+    // Returns the bytes which a key sends, or None for keys which don't send
+    // anything. enter is what the Enter key sends.
+    pub fn encode(&self, key_event: &KeyEvent, enter: &[u8]) -> Option<Vec<u8>> {
+        if let Some((_, data)) = self
+            .overrides
+            .iter()
+            .find(|(spec, _)| spec.matches(key_event))
+        {
+            // An empty string stops the key from sending anything.
+            return (!data.is_empty()).then(|| data.clone());
+        }
+        let modifiers = key_event.modifiers;
+        // Only xterm adds modifiers to escape sequences.
+        let param = match self.keymap {
+            None | Some(Keymap::Xterm) => modifier_param(modifiers),
+            _ => None,
+        };
+        let mut data = match sequence(key_event.code, self.keymap) {
+            Some(sequence) => {
+                let text = match (sequence, param) {
+                    (Sequence::Csi(ch), Some(param)) | (Sequence::Ss3(ch), Some(param)) => {
+                        format!("\x1b[1;{}{}", param, ch)
+                    }
+                    (Sequence::Tilde(number), Some(param)) => format!("\x1b[{};{}~", number, param),
+                    (Sequence::Csi(ch), None) => format!("\x1b[{}", ch),
+                    (Sequence::Ss3(ch), None) => format!("\x1bO{}", ch),
+                    (Sequence::Tilde(number), None) => format!("\x1b[{}~", number),
+                    (Sequence::Fixed(text), _) => text.to_string(),
+                };
+                if param.is_some() {
+                    return Some(text.into_bytes());
+                }
+                text.into_bytes()
+            }
+            None => match key_event.code {
+                KeyCode::BackTab => return Some(b"\x1b[Z".to_vec()),
+                KeyCode::Backspace => match self.keymap {
+                    None | Some(Keymap::Vt100) => vec![0x08],
+                    _ => vec![0x7f],
+                },
+                KeyCode::Enter => enter.to_vec(),
+                KeyCode::Tab => vec![b'\t'],
+                KeyCode::Esc => vec![0x1b],
+                KeyCode::Char(ch) if is_altgr_char(key_event) => {
+                    return Some(ch.to_string().into_bytes())
+                }
+                KeyCode::Char(ch) if modifiers.contains(KeyModifiers::CONTROL) => {
+                    match control_char(ch) {
+                        Some(byte) => vec![byte],
+                        None => ch.to_string().into_bytes(),
+                    }
+                }
+                KeyCode::Char(ch) => ch.to_string().into_bytes(),
+                _ => return None,
+            },
+        };
+        // Alt (meta) sends ESC before the key.
+        if modifiers.contains(KeyModifiers::ALT) {
+            data.insert(0, 0x1b);
+        }
+        Some(data)
+    }
+}
+
+// Returns the SGR (ESC [<b;x;yM) sequence which xterm sends for a mouse
+// event, or None for events which aren't sent (i.e. moving the mouse
+// without a button held down).
+pub fn encode_mouse(mouse_event: &MouseEvent) -> Option<Vec<u8>> {
+    let button_code = |button: MouseButton| match button {
+        MouseButton::Left => 0,
+        MouseButton::Middle => 1,
+        MouseButton::Right => 2,
+    };
+    let (mut code, last) = match mouse_event.kind {
+        MouseEventKind::Down(button) => (button_code(button), 'M'),
+        MouseEventKind::Up(button) => (button_code(button), 'm'),
+        MouseEventKind::Drag(button) => (button_code(button) + 32, 'M'),
+        MouseEventKind::ScrollUp => (64, 'M'),
+        MouseEventKind::ScrollDown => (65, 'M'),
+        MouseEventKind::ScrollLeft => (66, 'M'),
+        MouseEventKind::ScrollRight => (67, 'M'),
+        MouseEventKind::Moved => return None,
+    };
+    if mouse_event.modifiers.contains(KeyModifiers::SHIFT) {
+        code += 4;
+    }
+    if mouse_event.modifiers.contains(KeyModifiers::ALT) {
+        code += 8;
+    }
+    if mouse_event.modifiers.contains(KeyModifiers::CONTROL) {
+        code += 16;
+    }
+    // crossterm counts from 0, while the sequence counts from 1.
+    Some(
+        format!(
+            "\x1b[<{};{};{}{}",
+            code,
+            mouse_event.column + 1,
+            mouse_event.row + 1,
+            last
+        )
+        .into_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(keymap: Option<Keymap>, code: KeyCode, modifiers: KeyModifiers) -> Vec<u8> {
+        let encoder = KeyEncoder {
+            keymap,
+            overrides: Vec::new(),
+        };
+        encoder
+            .encode(&KeyEvent::new(code, modifiers), b"\r")
+            .unwrap()
+    }
+
+    fn mouse(kind: MouseEventKind, modifiers: KeyModifiers) -> Vec<u8> {
+        encode_mouse(&MouseEvent {
+            kind,
+            column: 9,
+            row: 4,
+            modifiers,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn modifier_params() {
+        for keymap in [None, Some(Keymap::Xterm)] {
+            assert_eq!(
+                encode(keymap, KeyCode::Up, KeyModifiers::CONTROL),
+                b"\x1b[1;5A"
+            );
+            assert_eq!(
+                encode(keymap, KeyCode::F(5), KeyModifiers::SHIFT),
+                b"\x1b[15;2~"
+            );
+            assert_eq!(
+                encode(keymap, KeyCode::F(1), KeyModifiers::ALT),
+                b"\x1b[1;3P"
+            );
+            assert_eq!(
+                encode(
+                    keymap,
+                    KeyCode::Home,
+                    KeyModifiers::SHIFT | KeyModifiers::CONTROL
+                ),
+                b"\x1b[1;6H"
+            );
+        }
+    }
+
+    #[test]
+    fn alt_prefixes_escape() {
+        assert_eq!(
+            encode(None, KeyCode::Char('x'), KeyModifiers::ALT),
+            b"\x1bx"
+        );
+        assert_eq!(
+            encode(
+                None,
+                KeyCode::Char('c'),
+                KeyModifiers::ALT | KeyModifiers::CONTROL
+            ),
+            b"\x1b\x03"
+        );
+        // Without modifier parameters, Alt prefixes the escape sequence too.
+        assert_eq!(
+            encode(Some(Keymap::Vt220), KeyCode::Up, KeyModifiers::ALT),
+            b"\x1b\x1b[A"
+        );
+    }
+
+    #[test]
+    fn altgr_chars() {
+        let altgr = KeyModifiers::CONTROL | KeyModifiers::ALT;
+        assert_eq!(encode(None, KeyCode::Char('@'), altgr), b"@");
+        assert_eq!(encode(None, KeyCode::Char('\\'), altgr), b"\\");
+        assert_eq!(encode(None, KeyCode::Char('{'), altgr), b"{");
+        assert_eq!(
+            encode(None, KeyCode::Char('\u{20ac}'), altgr),
+            "\u{20ac}".as_bytes()
+        );
+        // Letters are still Control-Alt.
+        assert_eq!(encode(None, KeyCode::Char('c'), altgr), b"\x1b\x03");
+    }
+
+    #[test]
+    fn control_chars() {
+        let expected = [
+            ('2', 0x00),
+            ('3', 0x1b),
+            ('4', 0x1c),
+            ('5', 0x1d),
+            ('6', 0x1e),
+            ('7', 0x1f),
+            ('8', 0x7f),
+        ];
+        for (ch, byte) in expected {
+            assert_eq!(control_char(ch), Some(byte), "Control-{}", ch);
+        }
+        assert_eq!(control_char('a'), Some(0x01));
+        assert_eq!(control_char('['), Some(0x1b));
+        assert_eq!(control_char('1'), None);
+        assert_eq!(control_char('9'), None);
+    }
+
+    // Checks the keys which differ between the keymaps.
+    fn check_keymap(keymap: Option<Keymap>, backspace: u8, home: &[u8], end: &[u8], f1: &[u8]) {
+        let none = KeyModifiers::NONE;
+        assert_eq!(encode(keymap, KeyCode::Backspace, none), [backspace]);
+        assert_eq!(encode(keymap, KeyCode::Home, none), home);
+        assert_eq!(encode(keymap, KeyCode::End, none), end);
+        assert_eq!(encode(keymap, KeyCode::F(1), none), f1);
+    }
+
+    #[test]
+    fn keymaps() {
+        check_keymap(None, 0x08, b"\x1b[H", b"\x1b[F", b"\x1bOP");
+        check_keymap(Some(Keymap::Xterm), 0x7f, b"\x1b[H", b"\x1b[F", b"\x1bOP");
+        check_keymap(Some(Keymap::Vt100), 0x08, b"\x1b[H", b"\x1b[F", b"\x1bOP");
+        check_keymap(
+            Some(Keymap::Vt220),
+            0x7f,
+            b"\x1b[1~",
+            b"\x1b[4~",
+            b"\x1b[11~",
+        );
+        check_keymap(
+            Some(Keymap::Linux),
+            0x7f,
+            b"\x1b[1~",
+            b"\x1b[4~",
+            b"\x1b[[A",
+        );
+        let none = KeyModifiers::NONE;
+        assert_eq!(
+            encode(Some(Keymap::Vt220), KeyCode::F(5), none),
+            b"\x1b[15~"
+        );
+        assert_eq!(encode(Some(Keymap::Linux), KeyCode::F(5), none), b"\x1b[[E");
+        assert_eq!(
+            encode(Some(Keymap::Linux), KeyCode::F(6), none),
+            b"\x1b[17~"
+        );
+    }
+
+    #[test]
+    fn keymaps_drop_modifiers() {
+        for keymap in [Keymap::Vt100, Keymap::Vt220, Keymap::Linux] {
+            assert_eq!(
+                encode(Some(keymap), KeyCode::Up, KeyModifiers::CONTROL),
+                b"\x1b[A"
+            );
+            assert_eq!(
+                encode(Some(keymap), KeyCode::PageUp, KeyModifiers::SHIFT),
+                b"\x1b[5~"
+            );
+        }
+    }
+
+    #[test]
+    fn sgr_mouse() {
+        let none = KeyModifiers::NONE;
+        assert_eq!(
+            mouse(MouseEventKind::Down(MouseButton::Left), none),
+            b"\x1b[<0;10;5M"
+        );
+        assert_eq!(
+            mouse(MouseEventKind::Up(MouseButton::Right), none),
+            b"\x1b[<2;10;5m"
+        );
+        assert_eq!(
+            mouse(MouseEventKind::Drag(MouseButton::Left), none),
+            b"\x1b[<32;10;5M"
+        );
+        assert_eq!(
+            mouse(MouseEventKind::Drag(MouseButton::Middle), none),
+            b"\x1b[<33;10;5M"
+        );
+        assert_eq!(mouse(MouseEventKind::ScrollUp, none), b"\x1b[<64;10;5M");
+        assert_eq!(mouse(MouseEventKind::ScrollDown, none), b"\x1b[<65;10;5M");
+        assert_eq!(
+            mouse(MouseEventKind::ScrollDown, KeyModifiers::CONTROL),
+            b"\x1b[<81;10;5M"
+        );
+        assert_eq!(
+            mouse(
+                MouseEventKind::Down(MouseButton::Left),
+                KeyModifiers::SHIFT | KeyModifiers::ALT
+            ),
+            b"\x1b[<12;10;5M"
+        );
+        assert_eq!(
+            encode_mouse(&MouseEvent {
+                kind: MouseEventKind::Moved,
+                column: 0,
+                row: 0,
+                modifiers: none,
+            }),
+            None
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use crate::key_encoder::control_char;
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// How long the exit key is held back waiting for it to be pressed again,
+// before it's sent to the port.
+const DOUBLE_PRESS_TIME: Duration = Duration::from_millis(500);
+
+/// A key (with modifiers) named in the configuration file or on the command
+/// line, i.e. F5, alt-r or ctrl-].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeySpec {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+// Names of keys which aren't single characters or function keys.
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("backspace", KeyCode::Backspace),
+    ("delete", KeyCode::Delete),
+    ("del", KeyCode::Delete),
+    ("down", KeyCode::Down),
+    ("end", KeyCode::End),
+    ("enter", KeyCode::Enter),
+    ("esc", KeyCode::Esc),
+    ("escape", KeyCode::Esc),
+    ("home", KeyCode::Home),
+    ("insert", KeyCode::Insert),
+    ("ins", KeyCode::Insert),
+    ("left", KeyCode::Left),
+    ("pagedown", KeyCode::PageDown),
+    ("pgdn", KeyCode::PageDown),
+    ("pageup", KeyCode::PageUp),
+    ("pgup", KeyCode::PageUp),
+    ("right", KeyCode::Right),
+    ("space", KeyCode::Char(' ')),
+    ("tab", KeyCode::Tab),
+    ("up", KeyCode::Up),
+];
+
+// Parses the name of a key without any modifiers.
+fn parse_code(name: &str) -> Option<KeyCode> {
+    let mut chars = name.chars();
+    if let (Some(ch), None) = (chars.next(), chars.next()) {
+        return Some(KeyCode::Char(ch.to_ascii_lowercase()));
+    }
+    let lower = name.to_ascii_lowercase();
+    if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return (1..=12).contains(&n).then_some(KeyCode::F(n));
+    }
+    KEY_NAMES
+        .iter()
+        .find(|(key_name, _)| *key_name == lower)
+        .map(|(_, code)| *code)
+}
+
+impl FromStr for KeySpec {
+    type Err = String;
+
+    // Modifiers come before the key, separated by - or +, i.e. ctrl-alt-x.
+    fn from_str(spec: &str) -> Result<KeySpec, String> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = spec;
+        loop {
+            let split = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, ch)| ch == '-' || ch == '+');
+            let (modifier, key) = match split {
+                Some((idx, _)) => (&rest[..idx], &rest[idx + 1..]),
+                None => break,
+            };
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" | "c" => KeyModifiers::CONTROL,
+                "alt" | "meta" | "m" => KeyModifiers::ALT,
+                "shift" | "s" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier '{}' in '{}'", modifier, spec)),
+            };
+            rest = key;
+        }
+        let code = parse_code(rest).ok_or_else(|| format!("unknown key '{}'", spec))?;
+        Ok(KeySpec { code, modifiers })
+    }
+}
+
+impl KeySpec {
+    // Returns whether a key which was pressed is this one. Shift is ignored
+    // for characters, since it's already reflected in the character. Control
+    // characters match however the terminal reports them (i.e. Control-] is
+    // reported as Control-5). Releasing a key doesn't match.
+    pub fn matches(&self, key_event: &KeyEvent) -> bool {
+        if key_event.kind == KeyEventKind::Release {
+            return false;
+        }
+        match (self.code, key_event.code) {
+            (KeyCode::Char(ch), KeyCode::Char(pressed)) => {
+                let ignored = KeyModifiers::SHIFT;
+                let same_char = if self.modifiers.contains(KeyModifiers::CONTROL) {
+                    match (control_char(ch), control_char(pressed)) {
+                        (Some(byte), Some(pressed_byte)) => byte == pressed_byte,
+                        _ => ch.eq_ignore_ascii_case(&pressed),
+                    }
+                } else {
+                    ch.eq_ignore_ascii_case(&pressed)
+                };
+                same_char && (self.modifiers - ignored) == (key_event.modifiers - ignored)
+            }
+            (code, pressed) => code == pressed && self.modifiers == key_event.modifiers,
+        }
+    }
+
+    // Returns whether this is a key used for typing, i.e. a character or
+    // Enter without Control or Alt.
+    pub fn is_plain(&self) -> bool {
+        let typing = matches!(
+            self.code,
+            KeyCode::Char(_) | KeyCode::Enter | KeyCode::Tab | KeyCode::Backspace | KeyCode::Esc
+        );
+        typing
+            && !self
+                .modifiers
+                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+    }
+
+    // Returns the event for pressing this key.
+    pub fn key_event(&self) -> KeyEvent {
+        KeyEvent::new(self.code, self.modifiers)
+    }
+}
+
+impl fmt::Display for KeySpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Control-"),
+            (KeyModifiers::ALT, "Alt-"),
+            (KeyModifiers::SHIFT, "Shift-"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{}", name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(ch) => write!(f, "{}", ch.to_ascii_uppercase()),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// What to do with a key, after checking whether it's the exit key.
+pub enum ExitCheck {
+    /// The exit key was pressed, so exit.
+    Exit,
+    /// The exit key was pressed once, and needs to be pressed again to exit.
+    Wait,
+    /// Handle the key as usual, after sending the exit key which was being
+    /// held back (if there is one).
+    Pass(Option<KeyEvent>),
+}
+
+/// Watches for the key which exits, which may need to be pressed twice in a
+/// row. Pressing it once then sends it to the port instead.
+pub struct ExitKey {
+    key: KeySpec,
+    double: bool,
+    /// The first press of the exit key, when waiting for the second.
+    pending: Option<KeyEvent>,
+    /// When the first press is sent, unless the key is pressed again.
+    send_at: Option<Instant>,
+}
+
+impl ExitKey {
+    pub fn new(key: KeySpec, double: bool) -> ExitKey {
+        ExitKey {
+            key,
+            double,
+            pending: None,
+            send_at: None,
+        }
+    }
+
+    pub fn check(&mut self, key_event: &KeyEvent) -> ExitCheck {
+        if !self.key.matches(key_event) {
+            return ExitCheck::Pass(self.take_held());
+        }
+        if self.double && self.pending.is_none() {
+            self.pending = Some(*key_event);
+            self.send_at = Some(Instant::now() + DOUBLE_PRESS_TIME);
+            ExitCheck::Wait
+        } else {
+            ExitCheck::Exit
+        }
+    }
+
+    // Waits until the held back press of the exit key should be sent, or
+    // forever if there isn't one.
+    pub async fn wait(&self) {
+        match self.send_at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    // Returns the press of the exit key which was held back, if there is one.
+    pub fn take_held(&mut self) -> Option<KeyEvent> {
+        self.send_at = None;
+        self.pending.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> Result<KeySpec, String> {
+        Ok(KeySpec { code, modifiers })
+    }
+
+    #[test]
+    fn parse() {
+        let none = KeyModifiers::NONE;
+        let ctrl = KeyModifiers::CONTROL;
+        let alt = KeyModifiers::ALT;
+        let specs = [
+            ("x", key(KeyCode::Char('x'), none)),
+            ("X", key(KeyCode::Char('x'), none)),
+            ("ctrl-x", key(KeyCode::Char('x'), ctrl)),
+            ("Control+X", key(KeyCode::Char('x'), ctrl)),
+            ("c-]", key(KeyCode::Char(']'), ctrl)),
+            ("ctrl-alt-x", key(KeyCode::Char('x'), ctrl | alt)),
+            ("m+s-tab", key(KeyCode::Tab, alt | KeyModifiers::SHIFT)),
+            ("alt-q", key(KeyCode::Char('q'), alt)),
+            ("meta-up", key(KeyCode::Up, alt)),
+            ("F5", key(KeyCode::F(5), none)),
+            ("shift-f12", key(KeyCode::F(12), KeyModifiers::SHIFT)),
+            ("f", key(KeyCode::Char('f'), none)),
+            ("PgDn", key(KeyCode::PageDown, none)),
+            ("ctrl-space", key(KeyCode::Char(' '), ctrl)),
+            // - and + are keys as well as separators.
+            ("-", key(KeyCode::Char('-'), none)),
+            ("alt--", key(KeyCode::Char('-'), alt)),
+            ("ctrl-+", key(KeyCode::Char('+'), ctrl)),
+        ];
+        for (spec, expected) in specs {
+            assert_eq!(spec.parse::<KeySpec>(), expected, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let errors = [
+            ("", "unknown key ''"),
+            ("ctrl-", "unknown key 'ctrl-'"),
+            ("f0", "unknown key 'f0'"),
+            ("f13", "unknown key 'f13'"),
+            ("fn", "unknown key 'fn'"),
+            ("ctrl-xy", "unknown key 'ctrl-xy'"),
+            ("hyper-x", "unknown modifier 'hyper' in 'hyper-x'"),
+            ("ctrl-super-x", "unknown modifier 'super' in 'ctrl-super-x'"),
+        ];
+        for (spec, error) in errors {
+            assert_eq!(spec.parse::<KeySpec>(), Err(error.to_string()), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn names_parse_back() {
+        for spec in [
+            "ctrl-x",
+            "alt-shift-f3",
+            "ctrl-space",
+            "pageup",
+            "esc",
+            "ctrl-alt-]",
+        ] {
+            let key: KeySpec = spec.parse().unwrap();
+            assert_eq!(key.to_string().parse(), Ok(key), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::history::History;
+use crate::key_encoder::is_altgr_char;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use unicode_width::UnicodeWidthChar;
+
+// The most output (in bytes) kept from the screen line holding the line being
+// edited. A longer line would have wrapped, so the start of it isn't redrawn.
+const MAX_TAIL: usize = 512;
+
+// Returns the character typed by a key. Keys with Control or Alt held down
+// don't type anything (apart from AltGr characters), and are left for the
+// key encoder.
+fn typed_char(key_event: &KeyEvent) -> Option<char> {
+    match key_event.code {
+        KeyCode::Char(ch)
+            if is_altgr_char(key_event)
+                || !key_event
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+        {
+            Some(ch)
+        }
+        _ => None,
+    }
+}
+
+// Returns how some of the line is shown, along with the number of columns
+// that it takes up. Tabs are shown as ^I, since how wide a tab is depends on
+// the column that it starts in, which isn't known when the output before the
+// line holds escape sequences.
+fn display(chars: impl Iterator<Item = char>) -> (String, usize) {
+    let mut text = String::new();
+    let mut width = 0;
+    for ch in chars {
+        if ch == '\t' {
+            text.push_str("^I");
+            width += 2;
+        } else {
+            text.push(ch);
+            width += ch.width().unwrap_or(0);
+        }
+    }
+    (text, width)
+}
+
+/// What happened to a key given to the line editor.
+pub enum EditResult {
+    /// The key was used to edit the line.
+    Handled,
+    /// Enter was pressed, and this line should be sent.
+    Send(String),
+    /// The editor doesn't use the key, so it should be sent as is.
+    NotHandled,
+}
+
+// State of a Control-R search through the history.
+struct Search {
+    query: String,
+    /// Index of the history line which matches the query.
+    found: Option<usize>,
+}
+
+/// Edits a line locally, only sending it to the port when Enter is pressed.
+/// The line being edited is drawn after the output received from the port.
+///
+/// Outside of line mode the editor is only opened to pick a line from the
+/// history to resend, and closes again once it's sent. While it's closed,
+/// the keys sent to the port are used to work out the lines which were
+/// typed, so that they can be added to the history.
+pub struct LineEditor {
+    /// Whether keys are going to the editor (always the case in line mode).
+    open: bool,
+    line_mode: bool,
+    /// The line being typed while the editor is closed, or None if it can't
+    /// be worked out (i.e. because the cursor was moved).
+    typed: Option<String>,
+    line: Vec<char>,
+    /// Position of the cursor within line.
+    cursor: usize,
+    history: History,
+    /// Index of the history line being shown while browsing with Up/Down.
+    browsing: Option<usize>,
+    /// The line which was being edited before browsing started.
+    draft: Vec<char>,
+    search: Option<Search>,
+    /// Output received since the last newline, which shares the screen line
+    /// with the line being edited.
+    tail: String,
+}
+
+impl LineEditor {
+    pub fn new(history: History, line_mode: bool) -> LineEditor {
+        LineEditor {
+            open: line_mode,
+            line_mode,
+            typed: Some(String::new()),
+            line: Vec::new(),
+            cursor: 0,
+            history,
+            browsing: None,
+            draft: Vec::new(),
+            search: None,
+            tail: String::new(),
+        }
+    }
+
+    // Returns what needs to be printed to redraw the screen line holding the
+    // line being edited, leaving the cursor in the right place.
+    pub fn render(&self) -> String {
+        let (before, (after, back)) = match &self.search {
+            Some(search) => {
+                let found = search
+                    .found
+                    .map_or("", |index| &self.history.lines()[index]);
+                let text = format!("(reverse-i-search)`{}': {}", search.query, found);
+                (display(text.chars()).0, (String::new(), 0))
+            }
+            None => (
+                display(self.line[..self.cursor].iter().copied()).0,
+                display(self.line[self.cursor..].iter().copied()),
+            ),
+        };
+        let mut output = format!("\r\x1b[K{}{}{}", self.tail, before, after);
+        if back > 0 {
+            output.push_str(&format!("\x1b[{}D", back));
+        }
+        output
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    // Opens the editor showing the most recent line in the history, so that
+    // it can be resent. Returns false if there's no history.
+    pub fn open_history(&mut self) -> bool {
+        if self.history.lines().is_empty() {
+            return false;
+        }
+        self.open = true;
+        self.browse(true);
+        true
+    }
+
+    // Closes the editor (unless in line mode), throwing away the line.
+    fn close(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.browsing = None;
+        self.open = self.line_mode;
+    }
+
+    // Keeps track of the line being typed from the keys sent to the port
+    // while the editor is closed, adding it to the history when Enter is
+    // pressed.
+    pub fn record_key(&mut self, key_event: &KeyEvent) {
+        if let Some(ch) = typed_char(key_event) {
+            if let Some(typed) = &mut self.typed {
+                typed.push(ch);
+            }
+            return;
+        }
+        let control = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        match key_event.code {
+            KeyCode::Enter => {
+                if let Some(typed) = self.typed.replace(String::new()) {
+                    self.history.push(&typed);
+                }
+            }
+            // Control-C and Control-U usually throw away the line.
+            KeyCode::Char('c') | KeyCode::Char('u') if control => self.typed = Some(String::new()),
+            KeyCode::Char(_) if control => {}
+            KeyCode::Backspace => {
+                if let Some(typed) = &mut self.typed {
+                    typed.pop();
+                }
+            }
+            // The device's line editor may be doing anything with these (Alt
+            // often moves by a word).
+            KeyCode::Char(_)
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::Up
+            | KeyCode::Down
+            | KeyCode::Home
+            | KeyCode::End
+            | KeyCode::Delete
+            | KeyCode::Tab => self.typed = None,
+            _ => {}
+        }
+    }
+
+    // Returns whether the output from the port ended with a new line.
+    pub fn at_line_start(&self) -> bool {
+        self.tail.is_empty()
+    }
+
+    // Returns what needs to be printed to show text received from the port
+    // without mixing it up with the line being edited.
+    pub fn output(&mut self, text: &str) -> String {
+        // The line being edited is only on the screen if there's something in it.
+        let editing = !self.line.is_empty() || self.search.is_some();
+        let mut output = if editing {
+            format!("\r\x1b[K{}", self.tail)
+        } else {
+            String::new()
+        };
+        output.push_str(text);
+        // Only the new text needs to be looked at for the end of a line.
+        match text.rfind(['\n', '\r']) {
+            Some(idx) => {
+                self.tail.clear();
+                self.tail.push_str(&text[idx + 1..]);
+            }
+            None => self.tail.push_str(text),
+        }
+        if self.tail.len() > MAX_TAIL {
+            let mut start = self.tail.len() - MAX_TAIL;
+            while !self.tail.is_char_boundary(start) {
+                start += 1;
+            }
+            self.tail.drain(..start);
+        }
+        if editing {
+            output.push_str(&self.render());
+        }
+        output
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    // Moves through the history (older for Up, newer for Down).
+    fn browse(&mut self, older: bool) {
+        let count = self.history.lines().len();
+        let index = match (self.browsing, older) {
+            (None, true) if count > 0 => {
+                self.draft = self.line.clone();
+                count - 1
+            }
+            (Some(index), true) if index > 0 => index - 1,
+            (Some(index), false) if index + 1 < count => index + 1,
+            (Some(_), false) => {
+                self.browsing = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.set_line(draft);
+                return;
+            }
+            _ => return,
+        };
+        self.browsing = Some(index);
+        self.set_line(self.history.lines()[index].chars().collect());
+    }
+
+    // Finds the newest history line containing the search query, starting
+    // at (and including) the line before index.
+    fn search_from(&mut self, before: usize) {
+        if let Some(search) = &mut self.search {
+            let lines = self.history.lines();
+            let found = (0..before.min(lines.len()))
+                .rev()
+                .find(|&index| lines[index].contains(&search.query));
+            if found.is_some() || search.query.is_empty() {
+                search.found = found;
+            }
+        }
+    }
+
+    // Handles a key pressed during a Control-R search.
+    fn search_key(&mut self, key_event: &KeyEvent) -> EditResult {
+        let newest = self.history.lines().len();
+        let control = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        let search = match &mut self.search {
+            Some(search) => search,
+            None => return EditResult::NotHandled,
+        };
+        let before = match (key_event.code, typed_char(key_event)) {
+            // Look for an older match.
+            (KeyCode::Char('r'), _) if control => search.found.unwrap_or(newest),
+            (_, Some(ch)) => {
+                search.query.push(ch);
+                search.found.map_or(newest, |index| index + 1)
+            }
+            (KeyCode::Backspace, _) => {
+                search.query.pop();
+                newest
+            }
+            (KeyCode::Esc, _) => {
+                self.search = None;
+                return EditResult::Handled;
+            }
+            _ => {
+                // Any other key accepts the line which was found, and then
+                // does what it would normally do.
+                if let Some(index) = self.search.take().and_then(|search| search.found) {
+                    self.set_line(self.history.lines()[index].chars().collect());
+                }
+                return self.handle_key(key_event);
+            }
+        };
+        self.search_from(before);
+        EditResult::Handled
+    }
+
+    // Edits the line based on a key which was pressed.
+    pub fn handle_key(&mut self, key_event: &KeyEvent) -> EditResult {
+        if self.search.is_some() {
+            return self.search_key(key_event);
+        }
+        if let Some(ch) = typed_char(key_event) {
+            self.line.insert(self.cursor, ch);
+            self.cursor += 1;
+            return EditResult::Handled;
+        }
+        let control = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        match key_event.code {
+            KeyCode::Enter => {
+                let line: String = self.line.iter().collect();
+                self.close();
+                self.history.push(&line);
+                return EditResult::Send(line);
+            }
+            KeyCode::Esc if !self.line_mode => {
+                self.close();
+            }
+            KeyCode::Char('r') if control => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                });
+            }
+            KeyCode::Char('a') if control => self.cursor = 0,
+            KeyCode::Char('e') if control => self.cursor = self.line.len(),
+            KeyCode::Char('u') if control => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char('k') if control => self.line.truncate(self.cursor),
+            // Other Control and Alt keys are sent as is.
+            KeyCode::Char(_) => return EditResult::NotHandled,
+            KeyCode::Tab => {
+                self.line.insert(self.cursor, '\t');
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.line.len(),
+            KeyCode::Up => self.browse(true),
+            KeyCode::Down => self.browse(false),
+            KeyCode::Backspace | KeyCode::Delete => {}
+            _ => return EditResult::NotHandled,
+        }
+        EditResult::Handled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    fn editor(keys: &[KeyEvent]) -> LineEditor {
+        let mut editor = LineEditor::new(History::load("line-editor-test"), true);
+        for key_event in keys {
+            editor.handle_key(key_event);
+        }
+        editor
+    }
+
+    fn typed(text: &str) -> Vec<KeyEvent> {
+        text.chars()
+            .map(|ch| match ch {
+                '\t' => key(KeyCode::Tab, KeyModifiers::NONE),
+                ch => key(KeyCode::Char(ch), KeyModifiers::NONE),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cursor_columns() {
+        let left = key(KeyCode::Left, KeyModifiers::NONE);
+        let mut keys = typed("a\tb\u{4e2d}c");
+        assert_eq!(editor(&keys).render(), "\r\x1b[Ka^Ib\u{4e2d}c");
+        keys.extend([left, left, left]);
+        // ^I takes two columns, and the wide character takes two.
+        assert_eq!(editor(&keys).render(), "\r\x1b[Ka^Ib\u{4e2d}c\x1b[4D");
+        keys.extend([left, left]);
+        assert_eq!(editor(&keys).render(), "\r\x1b[Ka^Ib\u{4e2d}c\x1b[7D");
+    }
+
+    #[test]
+    fn modified_keys() {
+        let mut editor = editor(&typed("ls"));
+        let alt_b = key(KeyCode::Char('b'), KeyModifiers::ALT);
+        let ctrl_c = key(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        let shifted = key(KeyCode::Char('A'), KeyModifiers::SHIFT);
+        let altgr = key(
+            KeyCode::Char('@'),
+            KeyModifiers::CONTROL | KeyModifiers::ALT,
+        );
+        assert!(matches!(editor.handle_key(&alt_b), EditResult::NotHandled));
+        assert!(matches!(editor.handle_key(&ctrl_c), EditResult::NotHandled));
+        assert!(matches!(editor.handle_key(&shifted), EditResult::Handled));
+        assert!(matches!(editor.handle_key(&altgr), EditResult::Handled));
+        assert_eq!(editor.line.iter().collect::<String>(), "lsA@");
+    }
+}
@@ -0,0 +1,33 @@
+use crate::error::{ProgramError, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+/// A file which records the data received from the port.
+pub struct SessionLog {
+    path: String,
+    file: File,
+}
+
+impl SessionLog {
+    // Opens a log file, appending to it if it already exists.
+    pub fn create(path: &str) -> Result<SessionLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| ProgramError::UnableToCreateLog(path.to_string(), e))?;
+        Ok(SessionLog {
+            path: path.to_string(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data)?;
+        Ok(())
+    }
+}
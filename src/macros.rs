@@ -0,0 +1,115 @@
+use crate::config::Config;
+use crate::control::unescape;
+use crate::error::{ProgramError, Result};
+use crate::keys::KeySpec;
+use crossterm::event::KeyEvent;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// One step of a macro.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MacroStep {
+    /// Send these bytes to the port.
+    Send(Vec<u8>),
+    /// Wait this long before carrying on with the next step.
+    Delay(Duration),
+}
+
+/// Keys which send a sequence of steps to the port instead of their usual
+/// escape sequence, from the [macros] table of the configuration file.
+#[derive(Debug, Default)]
+pub struct Macros {
+    macros: Vec<(KeySpec, Vec<MacroStep>)>,
+}
+
+// Parses a single step of a macro, which is either a string to send (with
+// the same escapes as the control socket) or a { delay = ms } table.
+fn parse_step(value: &toml::Value) -> std::result::Result<MacroStep, String> {
+    match value {
+        toml::Value::String(text) => Ok(MacroStep::Send(unescape(text)?)),
+        toml::Value::Table(table) => match (table.get("delay"), table.len()) {
+            (Some(toml::Value::Integer(ms)), 1) if *ms >= 0 => {
+                Ok(MacroStep::Delay(Duration::from_millis(*ms as u64)))
+            }
+            _ => Err("expecting { delay = milliseconds }".to_string()),
+        },
+        _ => Err("expecting a string, or a list of strings and delays".to_string()),
+    }
+}
+
+impl Macros {
+    // Collects the macros from the [macros] table, along with those from
+    // the profile's macros table (which override them).
+    pub fn load(config: &Config, profile: Option<&str>) -> Result<Macros> {
+        let table = config.section("macros", profile)?;
+        let mut macros = Macros::default();
+        for (key, value) in &table {
+            let error =
+                |reason: String| ProgramError::ConfigError(format!("macro '{}'", key), reason);
+            let spec: KeySpec = key.parse().map_err(error)?;
+            let steps = match value {
+                toml::Value::Array(values) => values
+                    .iter()
+                    .map(parse_step)
+                    .collect::<std::result::Result<Vec<_>, _>>(),
+                value => parse_step(value).map(|step| vec![step]),
+            }
+            .map_err(error)?;
+            macros.macros.push((spec, steps));
+        }
+        Ok(macros)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.macros.is_empty()
+    }
+
+    // Returns the steps of the macro for a key, if there is one.
+    pub fn find(&self, key_event: &KeyEvent) -> Option<&[MacroStep]> {
+        self.macros
+            .iter()
+            .find(|(spec, _)| spec.matches(key_event))
+            .map(|(_, steps)| steps.as_slice())
+    }
+}
+
+/// Works through the steps of the macros which have been started, so that
+/// delays don't hold up anything else.
+#[derive(Default)]
+pub struct MacroPlayer {
+    steps: VecDeque<MacroStep>,
+    /// When the current delay finishes, if there is one.
+    resume_at: Option<Instant>,
+}
+
+impl MacroPlayer {
+    // Queues up the steps of a macro, after any which are still to be done.
+    pub fn start(&mut self, steps: &[MacroStep]) {
+        self.steps.extend(steps.iter().cloned());
+    }
+
+    // Returns the data to send next, or None if there's nothing to send
+    // until the current delay finishes (or there are no more steps).
+    pub fn next_data(&mut self) -> Option<Vec<u8>> {
+        if self.resume_at.is_some_and(|at| at > Instant::now()) {
+            return None;
+        }
+        self.resume_at = None;
+        match self.steps.pop_front()? {
+            MacroStep::Send(data) => Some(data),
+            MacroStep::Delay(delay) => {
+                self.resume_at = Some(Instant::now() + delay);
+                None
+            }
+        }
+    }
+
+    // Waits until the current delay finishes, or forever if there isn't one.
+    pub async fn wait(&self) {
+        match self.resume_at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => futures::future::pending().await,
+        }
+    }
+}
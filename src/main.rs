@@ -1,26 +1,70 @@
 #![recursion_limit = "256"] // Needed for select!
 
 use crossterm::{
-    event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers},
+    event::{DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers},
+    execute,
     terminal::{disable_raw_mode, enable_raw_mode},
 };
-use futures::{future::FutureExt, select, StreamExt};
 use mio_serial::SerialPortInfo;
+use regex::{Regex, RegexBuilder};
 use serialport::{SerialPortType, UsbPortInfo};
+use std::collections::HashSet;
 use std::convert::TryFrom;
+use std::ffi::OsString;
 use std::io;
+use std::io::IsTerminal;
 use std::io::Write;
 use std::result::Result as StdResult;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use tokio_serial::{DataBits, FlowControl, Parity, StopBits};
 use tokio_util::bytes::Bytes;
-use tokio_util::codec::BytesCodec;
 use wildmatch::WildMatch;
 
+mod bridge;
+mod config;
+mod control;
 mod error;
+mod history;
+mod key_encoder;
+mod keys;
+mod line_editor;
+mod log;
+mod macros;
+mod menu;
+mod merged_log;
+mod monitor;
+mod multi;
+mod picker;
+mod resize;
+mod rfc2217;
+mod scrollback;
+mod sniffer;
+mod status_bar;
 mod string_decoder;
+mod telnet;
+mod transport;
+mod usb_ids;
+mod usb_location;
+mod watch;
+use bridge::{ListenClients, TcpBridge};
+use config::{profile_args, Config};
+use control::ControlServer;
 use error::{ProgramError, Result};
-use string_decoder::StringDecoder;
+use key_encoder::{KeyEncoder, Keymap};
+use keys::KeySpec;
+use macros::Macros;
+use menu::{menu_code, menu_label};
+use merged_log::{merged_log, LogTag};
+use monitor::{monitor, Session};
+use multi::monitor_multi;
+use picker::pick;
+use resize::ResizeForward;
+use sniffer::{sniff, SniffFormat};
+use transport::{LineSettings, Transport};
+use usb_ids::chip_name;
+use usb_location::{usb_location, UsbLocation};
+use watch::watch_ports;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "serial-monitor")]
@@ -29,6 +73,15 @@ struct Opt {
     #[structopt(short, long)]
     port: Option<String>,
 
+    /// Use the settings from this profile in the configuration file. Options
+    /// given on the command line override the profile.
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// Ignore the profile's setting for this option (i.e. --unset echo)
+    #[structopt(long, value_name = "option", number_of_values = 1)]
+    unset: Vec<String>,
+
     /// Baud rate to use
     #[structopt(short, long, default_value = "115200")]
     baud: u32,
@@ -45,10 +98,50 @@ struct Opt {
     #[structopt(short, long)]
     list: bool,
 
+    /// With --list, keep running and report ports as they are added and removed
+    #[structopt(short, long)]
+    watch: bool,
+
+    /// With --list --watch, report each change as a line of JSON
+    #[structopt(long, requires = "watch")]
+    json: bool,
+
+    /// Edit lines locally (with history), only sending them to the port when
+    /// Enter is pressed
+    #[structopt(long)]
+    line_mode: bool,
+
+    /// Without --line-mode, also add the lines typed to the history (they're
+    /// worked out from the keys sent, and may include passwords)
+    #[structopt(long)]
+    record_typed: bool,
+
+    /// Number of lines of output kept for scrolling back through with Control-T s (0 turns it off)
+    #[structopt(long, default_value = "10000", value_name = "lines")]
+    scrollback: usize,
+
+    /// Show a status line at the bottom of the screen, with the port settings, modem lines, byte
+    /// counts and how long the port has been connected
+    #[structopt(long)]
+    status_bar: bool,
+
+    /// Capture the mouse, and send clicks and scrolling to the port as xterm (SGR) mouse sequences
+    #[structopt(long)]
+    mouse: bool,
+
     /// Enter character to send (cr, lf, crlf)
     #[structopt(long, default_value = "cr")]
     enter: Eol,
 
+    /// Send keys the way this terminal does (vt100, vt220, xterm, linux)
+    #[structopt(long)]
+    keymap: Option<Keymap>,
+
+    /// Tell the device when the terminal is resized, by typing a stty command or sending the xterm
+    /// size report (stty, xterm)
+    #[structopt(long, value_name = "how")]
+    forward_resize: Option<ResizeForward>,
+
     /// Like list, but only prints the name of the port that was found.
     /// This is useful for using from scripts or makefiles.
     #[structopt(short, long)]
@@ -62,6 +155,14 @@ struct Opt {
     #[structopt(short = "y")]
     ctrl_y_exit: bool,
 
+    /// Key which exits (i.e. ctrl-] or alt-q) [default: ctrl-x]
+    #[structopt(long, value_name = "key", conflicts_with = "ctrl-y-exit")]
+    exit_key: Option<KeySpec>,
+
+    /// Only exit when the exit key is pressed twice in a row. Pressing it once sends it to the port
+    #[structopt(long)]
+    exit_double: bool,
+
     /// Filter based on Vendor ID (VID)
     #[structopt(long)]
     vid: Option<String>,
@@ -82,9 +183,90 @@ struct Opt {
     #[structopt(long)]
     product: Option<String>,
 
-    /// Return the index'th result
+    /// Filter based on USB interface number (for devices with several ports)
+    #[structopt(long)]
+    interface: Option<String>,
+
+    /// Filter based on where the device is plugged in (i.e. 1-3.2 for port 2 of
+    /// the hub on port 3 of bus 1). Only supported on Linux.
+    #[structopt(long)]
+    location: Option<String>,
+
+    /// Filter based on the sysfs path of the device. Only supported on Linux.
+    #[structopt(long)]
+    sysfs: Option<String>,
+
+    /// Filter based on the name of the USB serial chip, as shown by --list
+    /// (i.e. cp2102 or ch340). Case is ignored.
     #[structopt(long)]
-    index: Option<usize>,
+    chip: Option<String>,
+
+    /// Exclude ports whose name matches
+    #[structopt(long, alias = "exclude-port")]
+    not_port: Option<String>,
+
+    /// Exclude devices whose Vendor ID (VID) matches
+    #[structopt(long, alias = "exclude-vid")]
+    not_vid: Option<String>,
+
+    /// Exclude devices whose Product ID (PID) matches
+    #[structopt(long, alias = "exclude-pid")]
+    not_pid: Option<String>,
+
+    /// Exclude devices whose manufacturer name matches
+    #[structopt(long, alias = "exclude-manufacturer")]
+    not_manufacturer: Option<String>,
+
+    /// Exclude devices whose serial number matches
+    #[structopt(long, alias = "exclude-serial")]
+    not_serial: Option<String>,
+
+    /// Exclude devices whose product name matches
+    #[structopt(long, alias = "exclude-product")]
+    not_product: Option<String>,
+
+    /// Exclude ports with this USB interface number
+    #[structopt(long, alias = "exclude-interface")]
+    not_interface: Option<String>,
+
+    /// Exclude devices plugged in at this location
+    #[structopt(long, alias = "exclude-location")]
+    not_location: Option<String>,
+
+    /// Exclude devices whose sysfs path matches
+    #[structopt(long, alias = "exclude-sysfs")]
+    not_sysfs: Option<String>,
+
+    /// Exclude devices whose USB serial chip name matches
+    #[structopt(long, alias = "exclude-chip")]
+    not_chip: Option<String>,
+
+    /// Filters must match the whole string, rather than containing the pattern,
+    /// and wildcards aren't used
+    #[structopt(long, conflicts_with = "regex")]
+    exact: bool,
+
+    /// Filters are regular expressions (i.e. --serial '^E66.*3$')
+    #[structopt(long)]
+    regex: bool,
+
+    /// Filters ignore the case of letters
+    #[structopt(short = "i", long)]
+    ignore_case: bool,
+
+    /// Return the index'th result. Several indices (i.e. 0,1,2) may be
+    /// given to monitor more than one port at once.
+    #[structopt(long, use_delimiter = true, number_of_values = 1)]
+    index: Vec<usize>,
+
+    /// Use the first matching port rather than asking which one to use when
+    /// several ports match
+    #[structopt(long)]
+    first: bool,
+
+    /// Monitor all of the matching ports at once
+    #[structopt(short, long)]
+    all: bool,
 
     /// Parity checking (none, odd, even)
     #[structopt(long, default_value = "none")]
@@ -101,6 +283,57 @@ struct Opt {
     /// Data bits (5, 6, 7, 8)
     #[structopt(long, default_value = "8")]
     databits: usize,
+
+    /// Listen for TCP connections on this address (i.e. 0.0.0.0:2217) and
+    /// bridge them to the serial port
+    #[structopt(long)]
+    listen: Option<String>,
+
+    /// Number of TCP clients allowed to connect at once (single, multi).
+    /// With multi, one client may write and the others only observe.
+    #[structopt(long, default_value = "single")]
+    listen_clients: ListenClients,
+
+    /// Use RFC 2217 with TCP clients, allowing them to change the baud
+    /// rate and other settings of the serial port
+    #[structopt(long, requires = "listen")]
+    rfc2217: bool,
+
+    /// Accept commands on a unix domain socket at this path, so that other
+    /// programs can control the session
+    #[structopt(long)]
+    control: Option<String>,
+
+    /// Don't run interactively, but log the data received from every matching
+    /// port to this file (or - for stdout) with timestamped and tagged lines
+    #[structopt(long)]
+    merged_log: Option<String>,
+
+    /// What to tag each line of the merged log with (port, serial)
+    #[structopt(long, default_value = "port")]
+    tag: LogTag,
+
+    /// Sit between the selected port (connected to the host) and this port
+    /// (connected to the device), forwarding and showing the traffic both ways
+    #[structopt(long, value_name = "device-port")]
+    sniff: Option<String>,
+
+    /// How the sniffer shows traffic (text, hex)
+    #[structopt(long, default_value = "text")]
+    sniff_format: SniffFormat,
+
+    /// Connect to a raw TCP port (i.e. on a console server) rather than a serial port
+    #[structopt(long, value_name = "host:port", conflicts_with_all = &["telnet", "URL"])]
+    tcp: Option<String>,
+
+    /// Connect to a telnet server rather than a serial port
+    #[structopt(long, value_name = "host:port", conflicts_with = "URL")]
+    telnet: Option<String>,
+
+    /// Connect to a remote port rather than a local one (i.e. rfc2217://host:port,
+    /// tcp://host:port or telnet://host:port)
+    #[structopt(name = "URL")]
+    url: Option<String>,
 }
 
 struct DataBitsOpt(DataBits);
@@ -114,10 +347,7 @@ impl TryFrom<usize> for DataBitsOpt {
             6 => Ok(Self(DataBits::Six)),
             7 => Ok(Self(DataBits::Seven)),
             8 => Ok(Self(DataBits::Eight)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "databits out of range",
-            )),
+            _ => Err(io::Error::other("databits out of range")),
         }
     }
 }
@@ -174,10 +404,7 @@ impl TryFrom<usize> for StopBitsOpt {
         match value {
             1 => Ok(Self(StopBits::One)),
             2 => Ok(Self(StopBits::Two)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "stopbits out of range",
-            )),
+            _ => Err(io::Error::other("stopbits out of range")),
         }
     }
 }
@@ -204,28 +431,84 @@ impl Eol {
     }
 }
 
-// Returns the lowercase version of the character which will cause
-// serial-monitor to exit.
-fn exit_char(opt: &Opt) -> char {
-    if opt.ctrl_y_exit {
-        'y'
+// Returns the line settings specified on the command line.
+fn line_settings(opt: &Opt) -> Result<LineSettings> {
+    Ok(LineSettings {
+        baud: opt.baud,
+        data_bits: DataBitsOpt::try_from(opt.databits)?.0,
+        parity: opt.parity.into(),
+        stop_bits: StopBitsOpt::try_from(opt.stopbits)?.0,
+        flow_control: opt.flow.into(),
+        dtr: None,
+        rts: None,
+        break_on: false,
+    })
+}
+
+// Returns the URL of the remote port to connect to (if any).
+fn remote_url(opt: &Opt) -> Option<String> {
+    if let Some(addr) = &opt.tcp {
+        Some(format!("tcp://{}", addr))
+    } else if let Some(addr) = &opt.telnet {
+        Some(format!("telnet://{}", addr))
     } else {
-        'x'
+        opt.url.clone()
     }
 }
 
-// Returns the Event::Key variant of the exit character which will
-// cause the serial monitor to exit.
-fn exit_code(opt: &Opt) -> Event {
-    Event::Key(KeyEvent::new(
-        KeyCode::Char(exit_char(opt)),
-        KeyModifiers::CONTROL,
-    ))
+// Returns the key which will cause serial-monitor to exit.
+fn exit_key(opt: &Opt) -> KeySpec {
+    opt.exit_key.unwrap_or(KeySpec {
+        code: KeyCode::Char(if opt.ctrl_y_exit { 'y' } else { 'x' }),
+        modifiers: KeyModifiers::CONTROL,
+    })
 }
 
-// Returns a human readable string of the exit character.
+// Returns a human readable string of the exit key.
 fn exit_label(opt: &Opt) -> String {
-    format!("Control-{}", exit_char(opt).to_ascii_uppercase())
+    if opt.exit_double {
+        format!("{} twice", exit_key(opt))
+    } else {
+        exit_key(opt).to_string()
+    }
+}
+
+// Makes sure that the exit key doesn't get in the way of the command menu,
+// or of typing.
+fn check_exit_key(opt: &Opt) -> Result<()> {
+    if exit_key(opt).is_plain() {
+        return Err(ProgramError::IncompatibleOptions(format!(
+            "The exit key can't be {}, which is needed for typing",
+            exit_key(opt)
+        )));
+    }
+    if let Event::Key(menu_key) = menu_code() {
+        if exit_key(opt).matches(&menu_key) {
+            return Err(ProgramError::IncompatibleOptions(format!(
+                "The exit key can't be {}, which starts a menu command",
+                menu_label()
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Makes sure that the exit key isn't one which the configuration file gives
+// something else to do.
+fn check_exit_bindings(opt: &Opt, macros: &Macros, encoder: &KeyEncoder) -> Result<()> {
+    let key_event = exit_key(opt).key_event();
+    let table = if macros.find(&key_event).is_some() {
+        "macros"
+    } else if encoder.overrides(&key_event) {
+        "keys"
+    } else {
+        return Ok(());
+    };
+    Err(ProgramError::IncompatibleOptions(format!(
+        "The exit key can't be {}, which is in the [{}] table",
+        exit_key(opt),
+        table
+    )))
 }
 
 // Converts a byte string into a string comprised of each byte
@@ -267,17 +550,13 @@ fn hex_str(bytes: &[u8]) -> String {
 // Checks to see if a string matches a pattern used for filtering.
 fn matches(str: &str, pattern: Option<String>, opt: &Opt) -> bool {
     let result = match pattern.clone() {
-        Some(pattern) => {
-            if pattern.contains('*') || pattern.contains('?') {
-                // If any wildcards are present, then we assume that the
-                // pattern is fully specified
-                WildMatch::new(&pattern).matches(str)
-            } else {
-                // Since no wildcard were specified we treat it as if there
-                // was a '*' at each end.
-                WildMatch::new(&format!("*{}*", pattern)).matches(str)
-            }
+        Some(pattern) if opt.regex => filter_regex(&pattern, opt)
+            .map(|regex| regex.is_match(str))
+            .unwrap_or(false),
+        Some(pattern) if opt.ignore_case => {
+            glob_matches(&str.to_lowercase(), &pattern.to_lowercase(), opt)
         }
+        Some(pattern) => glob_matches(str, &pattern, opt),
         None => {
             // If no pattern is specified, then we consider that
             // a match has taken place.
@@ -293,6 +572,92 @@ fn matches(str: &str, pattern: Option<String>, opt: &Opt) -> bool {
     result
 }
 
+// Checks to see if a USB vendor or product ID matches a pattern. Since IDs
+// never contain commas, the pattern may list several alternatives separated
+// by commas (regular expressions use | instead).
+fn id_matches(id: u16, pattern: &Option<String>, opt: &Opt) -> bool {
+    let id = format!("{:04x}", id);
+    match pattern {
+        Some(pattern) if !opt.regex => pattern
+            .split(',')
+            .any(|alt| matches(&id, Some(alt.trim().to_string()), opt)),
+        _ => matches(&id, pattern.clone(), opt),
+    }
+}
+
+// Checks to see if a string matches a glob pattern (or is identical to it
+// when using --exact).
+fn glob_matches(str: &str, pattern: &str, opt: &Opt) -> bool {
+    if opt.exact {
+        str == pattern
+    } else if pattern.contains('*') || pattern.contains('?') {
+        // If any wildcards are present, then we assume that the
+        // pattern is fully specified
+        WildMatch::new(pattern).matches(str)
+    } else {
+        // Since no wildcard were specified we treat it as if there
+        // was a '*' at each end.
+        WildMatch::new(&format!("*{}*", pattern)).matches(str)
+    }
+}
+
+// Compiles a pattern used for filtering when using --regex.
+fn filter_regex(pattern: &str, opt: &Opt) -> StdResult<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(opt.ignore_case)
+        .build()
+}
+
+// Checks that all of the patterns used for filtering are valid regular
+// expressions when using --regex, so that a typo isn't mistaken for a
+// pattern which doesn't match anything.
+fn check_filters(opt: &Opt) -> Result<()> {
+    for pattern in [&opt.interface, &opt.not_interface]
+        .iter()
+        .copied()
+        .flatten()
+    {
+        if pattern
+            .split(',')
+            .any(|alt| alt.trim().parse::<u8>().is_err())
+        {
+            return Err(ProgramError::InvalidFilter(
+                pattern.clone(),
+                "expecting interface numbers".to_string(),
+            ));
+        }
+    }
+    if !opt.regex {
+        return Ok(());
+    }
+    let patterns = [
+        &opt.port,
+        &opt.vid,
+        &opt.pid,
+        &opt.manufacturer,
+        &opt.serial,
+        &opt.product,
+        &opt.location,
+        &opt.sysfs,
+        &opt.chip,
+        &opt.not_port,
+        &opt.not_vid,
+        &opt.not_pid,
+        &opt.not_manufacturer,
+        &opt.not_serial,
+        &opt.not_product,
+        &opt.not_location,
+        &opt.not_sysfs,
+        &opt.not_chip,
+    ];
+    for pattern in patterns.iter().copied().flatten() {
+        if let Err(e) = filter_regex(pattern, opt) {
+            return Err(ProgramError::InvalidFilter(pattern.clone(), e.to_string()));
+        }
+    }
+    Ok(())
+}
+
 // Similar to matches but checks to see if an Option<String> matches a pattern.
 fn matches_opt(str: Option<String>, pattern: Option<String>, opt: &Opt) -> bool {
     if let Some(str) = str {
@@ -345,15 +710,64 @@ fn available_ports() -> Result<Vec<SerialPortInfo>> {
     Ok(mio_serial::available_ports()?)
 }
 
+// Returns the pattern used to match chip names, which always ignores case.
+// The names are lowercased before being compared with it.
+fn chip_pattern(pattern: &Option<String>, opt: &Opt) -> Option<String> {
+    pattern.as_ref().map(|pattern| {
+        if opt.regex {
+            format!("(?i){}", pattern)
+        } else {
+            pattern.to_lowercase()
+        }
+    })
+}
+
+// Checks to see if an interface number is one of those listed in pattern.
+fn interface_matches(interface: Option<u8>, pattern: &str) -> bool {
+    pattern
+        .split(',')
+        .any(|alt| alt.trim().parse::<u8>().ok() == interface)
+}
+
+// Checks to see if a string matches an exclusion pattern. Missing strings
+// (like a device without a serial number) are never excluded.
+fn excluded(str: Option<String>, pattern: Option<String>, opt: &Opt) -> bool {
+    match (str, &pattern) {
+        (Some(str), Some(_)) => matches(&str, pattern, opt),
+        _ => false,
+    }
+}
+
 // Checks to see if a serial port matches the filtering criteria specified on the command line.
 fn usb_port_matches(port: &SerialPortInfo, opt: &Opt) -> bool {
     if let SerialPortType::UsbPort(info) = &port.port_type {
+        let location = usb_location(&port.port_name, info);
+        let chip = chip_name(info.vid, info.pid).map(str::to_lowercase);
         if matches(&port.port_name, opt.port.clone(), opt)
-            && matches(&format!("{:04x}", info.vid), opt.vid.clone(), opt)
-            && matches(&format!("{:04x}", info.pid), opt.pid.clone(), opt)
+            && id_matches(info.vid, &opt.vid, opt)
+            && id_matches(info.pid, &opt.pid, opt)
             && matches_opt(info.manufacturer.clone(), opt.manufacturer.clone(), opt)
             && matches_opt(info.serial_number.clone(), opt.serial.clone(), opt)
             && matches_opt(info.product.clone(), opt.product.clone(), opt)
+            && opt
+                .interface
+                .as_ref()
+                .is_none_or(|pattern| interface_matches(location.interface, pattern))
+            && matches_opt(location.location.clone(), opt.location.clone(), opt)
+            && matches_opt(location.sysfs_path.clone(), opt.sysfs.clone(), opt)
+            && matches_opt(chip.clone(), chip_pattern(&opt.chip, opt), opt)
+            && !excluded(Some(port.port_name.clone()), opt.not_port.clone(), opt)
+            && !(opt.not_vid.is_some() && id_matches(info.vid, &opt.not_vid, opt))
+            && !(opt.not_pid.is_some() && id_matches(info.pid, &opt.not_pid, opt))
+            && !excluded(info.manufacturer.clone(), opt.not_manufacturer.clone(), opt)
+            && !excluded(info.serial_number.clone(), opt.not_serial.clone(), opt)
+            && !excluded(info.product.clone(), opt.not_product.clone(), opt)
+            && !opt.not_interface.as_ref().is_some_and(|pattern| {
+                location.interface.is_some() && interface_matches(location.interface, pattern)
+            })
+            && !excluded(location.location, opt.not_location.clone(), opt)
+            && !excluded(location.sysfs_path, opt.not_sysfs.clone(), opt)
+            && !excluded(chip, chip_pattern(&opt.not_chip, opt), opt)
         {
             return true;
         }
@@ -361,18 +775,24 @@ fn usb_port_matches(port: &SerialPortInfo, opt: &Opt) -> bool {
     false
 }
 
-fn filtered_ports(opt: &Opt) -> Result<Vec<SerialPortInfo>> {
+// Returns all of the ports which match the filtering criteria (ignoring
+// --index), sorted by name. The list may be empty.
+fn matching_ports(opt: &Opt) -> Result<Vec<SerialPortInfo>> {
     let mut ports: Vec<SerialPortInfo> = available_ports()?
         .into_iter()
         .filter(|info| usb_port_matches(info, opt))
         .collect();
     ports.sort_by(|a, b| a.port_name.cmp(&b.port_name));
-    if let Some(index) = opt.index {
-        if index < ports.len() {
-            Ok(vec![ports[index].clone()])
-        } else {
-            Err(ProgramError::NoPortFound)
-        }
+    Ok(ports)
+}
+
+fn filtered_ports(opt: &Opt) -> Result<Vec<SerialPortInfo>> {
+    let ports = matching_ports(opt)?;
+    if !opt.index.is_empty() {
+        opt.index
+            .iter()
+            .map(|index| ports.get(*index).cloned().ok_or(ProgramError::NoPortFound))
+            .collect()
     } else if ports.is_empty() {
         Err(ProgramError::NoPortFound)
     } else {
@@ -380,14 +800,40 @@ fn filtered_ports(opt: &Opt) -> Result<Vec<SerialPortInfo>> {
     }
 }
 
+// Returns the port which matches the filtering criteria. If several ports
+// match, then the user gets to pick one when running interactively, and the
+// first one is used otherwise (or when using --first).
 fn filtered_port(opt: &Opt) -> Result<SerialPortInfo> {
-    Ok(filtered_ports(opt)?[0].clone())
+    let mut ports = filtered_ports(opt)?;
+    let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+    let index = if ports.len() > 1 && interactive && !opt.first {
+        let choices: Vec<String> = ports.iter().map(port_choice).collect();
+        pick("Several ports match:", &choices)?
+    } else {
+        0
+    };
+    Ok(ports.swap_remove(index))
+}
+
+// Describes a port for the list shown by the port picker.
+fn port_choice(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => format!(
+            "{}{}",
+            port.port_name,
+            extra_usb_info(info, &usb_location(&port.port_name, info))
+        ),
+        _ => port.port_name.clone(),
+    }
 }
 
 // Formats the USB Port information into a human readable form.
-fn extra_usb_info(info: &UsbPortInfo) -> String {
+fn extra_usb_info(info: &UsbPortInfo, location: &UsbLocation) -> String {
     let mut output = String::new();
     output = output + &format!(" {:04x}:{:04x}", info.vid, info.pid);
+    if let Some(chip) = chip_name(info.vid, info.pid) {
+        output = output + &format!(" ({})", chip);
+    }
     let mut extra_items = Vec::new();
 
     if let Some(manufacturer) = &info.manufacturer {
@@ -399,6 +845,12 @@ fn extra_usb_info(info: &UsbPortInfo) -> String {
     if let Some(product) = &info.product {
         extra_items.push(format!("product '{}'", product));
     }
+    if let Some(interface) = location.interface {
+        extra_items.push(format!("interface {}", interface));
+    }
+    if let Some(usb_location) = &location.location {
+        extra_items.push(format!("location '{}'", usb_location));
+    }
     if !extra_items.is_empty() {
         output += " with ";
         output += &extra_items.join(" ");
@@ -406,160 +858,93 @@ fn extra_usb_info(info: &UsbPortInfo) -> String {
     output
 }
 
+// Describes a port in the form used by --list.
+fn port_description(port: &SerialPortInfo) -> String {
+    if let SerialPortType::UsbPort(info) = &port.port_type {
+        format!(
+            "USB Serial Device{} found @{}",
+            extra_usb_info(info, &usb_location(&port.port_name, info)),
+            port.port_name
+        )
+    } else {
+        format!("Serial Device found @{}", port.port_name)
+    }
+}
+
 // Lists all of the USB serial ports which match the filtering criteria.
 fn list_ports(opt: &Opt) -> Result<()> {
     for port in filtered_ports(opt)? {
-        if let SerialPortType::UsbPort(info) = &port.port_type {
-            println!(
-                "USB Serial Device{} found @{}",
-                extra_usb_info(info),
-                port.port_name
-            );
-        } else {
-            println!("Serial Device found @{}", port.port_name);
+        println!("{}", port_description(&port));
+        if let (true, SerialPortType::UsbPort(info)) = (opt.verbose, &port.port_type) {
+            if let Some(sysfs_path) = usb_location(&port.port_name, info).sysfs_path {
+                println!("    sysfs path {}", sysfs_path);
+            }
         }
     }
     Ok(())
 }
 
-// Returns the first port which matches the filtering criteria.
+// Returns the port which matches the filtering criteria.
 fn find_port(opt: &Opt) -> Result<String> {
     Ok(filtered_port(opt)?.port_name)
 }
 
+// Returns the name of the port to connect to. Ports which aren't enumerated
+// as USB serial devices (like a pty) can still be opened by passing their
+// full path using --port.
+fn connect_port_name(opt: &Opt) -> Result<String> {
+    match find_port(opt) {
+        Err(ProgramError::NoPortFound) => match &opt.port {
+            Some(port) if std::path::Path::new(port).exists() => Ok(port.clone()),
+            _ => Err(ProgramError::NoPortFound),
+        },
+        result => result,
+    }
+}
+
+// Returns the name of the history file used for lines sent to a port. This
+// is the profile, or the USB serial number of the device. Devices without a
+// serial number share the default history.
+fn history_name(opt: &Opt, port_name: &str) -> String {
+    if let Some(profile) = &opt.profile {
+        return profile.clone();
+    }
+    let serial_number = available_ports().ok().and_then(|ports| {
+        ports.into_iter().find_map(|port| match port.port_type {
+            SerialPortType::UsbPort(info) if port.port_name == port_name => info.serial_number,
+            _ => None,
+        })
+    });
+    match serial_number {
+        Some(serial_number) => format!("serial-{}", serial_number),
+        None => "default".to_string(),
+    }
+}
+
 // Converts key events from crossterm into appropriate character/escape sequences which are then
 // sent over the serial connection.
-fn handle_key_event(key_event: KeyEvent, opt: &Opt) -> Result<Option<Bytes>> {
+fn handle_key_event(key_event: KeyEvent, encoder: &KeyEncoder, opt: &Opt) -> Result<Option<Bytes>> {
     if opt.debug {
         println!("Event::{:?}\r", key_event);
     }
 
-    // The following escape sequeces come from the MicroPython codebase.
-    //
-    //  Up      ESC [A
-    //  Down    ESC [B
-    //  Right   ESC [C
-    //  Left    ESC [D
-    //  Home    ESC [H  or ESC [1~
-    //  End     ESC [F  or ESC [4~
-    //  Del     ESC [3~
-    //  Insert  ESC [2~
-
-    let mut buf = [0; 4];
-
-    let key_str: Option<&[u8]> = match key_event.code {
-        KeyCode::Backspace => Some(b"\x08"),
-        KeyCode::Enter => Some(opt.enter.bytes()),
-        KeyCode::Left => Some(b"\x1b[D"),
-        KeyCode::Right => Some(b"\x1b[C"),
-        KeyCode::Home => Some(b"\x1b[H"),
-        KeyCode::End => Some(b"\x1b[F"),
-        KeyCode::Up => Some(b"\x1b[A"),
-        KeyCode::Down => Some(b"\x1b[B"),
-        KeyCode::Tab => Some(b"\x09"),
-        KeyCode::Delete => Some(b"\x1b[3~"),
-        KeyCode::Insert => Some(b"\x1b[2~"),
-        KeyCode::Esc => Some(b"\x1b"),
-        KeyCode::Char(ch) => {
-            if key_event.modifiers & KeyModifiers::CONTROL == KeyModifiers::CONTROL {
-                buf[0] = ch as u8;
-                if ch.is_ascii_lowercase() || (ch == ' ') {
-                    buf[0] &= 0x1f;
-                    Some(&buf[0..1])
-                } else if ('4'..='7').contains(&ch) {
-                    // crossterm returns Control-4 thru 7 for \x1c thru \x1f
-                    buf[0] = (buf[0] + 8) & 0x1f;
-                    Some(&buf[0..1])
-                } else {
-                    Some(ch.encode_utf8(&mut buf).as_bytes())
-                }
-            } else {
-                Some(ch.encode_utf8(&mut buf).as_bytes())
-            }
-        }
-        _ => None,
-    };
+    let key_str = encoder.encode(&key_event, opt.enter.bytes());
     if let Some(key_str) = key_str {
         if opt.debug {
-            println!("Send: {}\r", hex_str(key_str));
+            println!("Send: {}\r", hex_str(&key_str));
         }
         if opt.echo {
-            if let Ok(val) = std::str::from_utf8(key_str) {
+            if let Ok(val) = std::str::from_utf8(&key_str) {
                 print!("{}", val);
                 std::io::stdout().flush()?;
             }
         }
-        Ok(Some(Bytes::copy_from_slice(key_str)))
+        Ok(Some(Bytes::from(key_str)))
     } else {
         Ok(None)
     }
 }
 
-// Main function which collects input from the user and sends it over the serial link
-// and collects serial data and presents it to the user.
-async fn monitor(port: &mut tokio_serial::SerialStream, opt: &Opt) -> Result<()> {
-    let mut reader = EventStream::new();
-    let (rx_port, tx_port) = tokio::io::split(port);
-
-    let mut serial_reader = tokio_util::codec::FramedRead::new(rx_port, StringDecoder::new());
-    let serial_sink = tokio_util::codec::FramedWrite::new(tx_port, BytesCodec::new());
-    let (serial_writer, serial_consumer) = futures::channel::mpsc::unbounded::<Bytes>();
-
-    let exit_code = exit_code(opt);
-
-    let mut poll_send = serial_consumer.map(Ok).forward(serial_sink);
-    loop {
-        let mut event = reader.next().fuse();
-        let mut serial_event = serial_reader.next().fuse();
-
-        select! {
-            _ = poll_send => {}
-            maybe_event = event => {
-                match maybe_event {
-                    Some(Ok(event)) => {
-                        if event == exit_code {
-                            break;
-                        }
-                        if let Event::Key(key_event) = event {
-                            if let Some(key) = handle_key_event(key_event, opt)? {
-                                serial_writer.unbounded_send(key).unwrap();
-                            }
-                        } else {
-                            println!("Unrecognized Event::{:?}\r", event);
-                        }
-                    }
-                    Some(Err(e)) => println!("crossterm Error: {:?}\r", e),
-                    None => {
-                        println!("maybe_event returned None\r");
-                    },
-                }
-            },
-            maybe_serial = serial_event => {
-                match maybe_serial {
-                    Some(Ok(serial_event)) => {
-                        if opt.debug {
-                            println!("Serial Event:{:?}\r", serial_event);
-                        } else {
-                            print!("{}", serial_event);
-                            std::io::stdout().flush()?;
-                        }
-                    },
-                    Some(Err(e)) => {
-                        println!("Serial Error: {:?}\r", e);
-                        // This most likely means that the serial port has been unplugged.
-                        break;
-                    },
-                    None => {
-                        println!("maybe_serial returned None\r");
-                    },
-                }
-            },
-        };
-    }
-
-    Ok(())
-}
-
 // Main entry point to the program.
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -570,6 +955,10 @@ async fn main() -> Result<()> {
             writeln!(&mut std::io::stderr(), "No USB serial ports found")?;
             std::process::exit(1);
         }
+        Err(ProgramError::NoPortSelected) => {
+            writeln!(&mut std::io::stderr(), "No port was selected")?;
+            std::process::exit(1);
+        }
         Err(err) => {
             writeln!(&mut std::io::stderr(), "Error: {:?}", err)?;
             std::process::exit(2);
@@ -577,13 +966,63 @@ async fn main() -> Result<()> {
     }
 }
 
+// Parses the command line, filling in options which weren't given from the
+// profile selected with --profile.
+fn parse_opt() -> Result<(Opt, Config)> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Opt::clap().get_matches_from(&args);
+    let opt = Opt::from_clap(&matches);
+    let config = Config::load();
+    let name = match &opt.profile {
+        Some(name) => name,
+        None => return Ok((opt, config)),
+    };
+    let mut merged_args = args.clone();
+    merged_args.extend(profile_args(
+        name,
+        config.profile(name)?,
+        &opt.unset,
+        &Opt::clap(),
+        &matches,
+    )?);
+    let merged = Opt::clap()
+        .setting(AppSettings::ColorNever)
+        .get_matches_from_safe(merged_args)
+        .map_err(|e| {
+            // Only the first line of the message is useful, since the usage
+            // which follows it describes the merged arguments.
+            let reason = e.message.lines().next().unwrap_or_default();
+            ProgramError::ConfigError(
+                format!("profile '{}'", name),
+                reason.trim_start_matches("error: ").to_string(),
+            )
+        })?;
+    Ok((Opt::from_clap(&merged), config))
+}
+
 async fn real_main() -> Result<()> {
-    let opt = Opt::from_args();
+    let (mut opt, config) = parse_opt()?;
+    // Giving the same index twice would open the same port twice.
+    let mut seen = HashSet::new();
+    opt.index.retain(|index| seen.insert(*index));
 
     if opt.verbose {
+        for file in &config.files {
+            println!("Read {}", file.display());
+        }
+        if let Some(name) = &opt.profile {
+            println!("Using profile '{}'", name);
+        }
         println!("{:#?}", opt);
     }
 
+    check_filters(&opt)?;
+    check_exit_key(&opt)?;
+
+    if opt.list && opt.watch {
+        return watch_ports(&opt).await;
+    }
+
     if opt.list {
         list_ports(&opt)?;
         return Ok(());
@@ -594,24 +1033,286 @@ async fn real_main() -> Result<()> {
         return Ok(());
     }
 
-    let port_name = find_port(&opt)?;
+    if let Some(path) = &opt.merged_log {
+        return merged_log(path, filtered_ports(&opt)?, &opt).await;
+    }
 
-    // Do the serial port monitoring
-    let port_builder = tokio_serial::new(&port_name, opt.baud)
-        .data_bits(DataBitsOpt::try_from(opt.databits)?.0)
-        .parity(opt.parity.into())
-        .stop_bits(StopBitsOpt::try_from(opt.stopbits)?.0)
-        .flow_control(opt.flow.into());
+    let settings = line_settings(&opt)?;
+    if opt.all || opt.index.len() > 1 {
+        return multi_main(&opt, &settings, &config).await;
+    }
+    if let Some(device_name) = &opt.sniff {
+        if remote_url(&opt).is_some() {
+            return Err(ProgramError::IncompatibleOptions(
+                "--sniff can't be used with a remote port".to_string(),
+            ));
+        }
+        return sniff(connect_port_name(&opt)?, device_name, &settings, &opt).await;
+    }
+    let port_name = match remote_url(&opt) {
+        Some(url) => url,
+        None => connect_port_name(&opt)?,
+    };
+    let macros = Macros::load(&config, opt.profile.as_deref())?;
+    let encoder = KeyEncoder::load(opt.keymap, &config, opt.profile.as_deref())?;
+    check_exit_bindings(&opt, &macros, &encoder)?;
+    let port = Transport::open(&port_name, &settings).await?;
+
+    let bridge = match &opt.listen {
+        Some(addr) => {
+            Some(TcpBridge::bind(addr, opt.listen_clients, opt.rfc2217, &settings).await?)
+        }
+        None => None,
+    };
+    let control = match &opt.control {
+        Some(path) => Some(ControlServer::bind(path)?),
+        None => None,
+    };
 
-    let err_port_name = port_name.clone();
-    let mut port = tokio_serial::SerialStream::open(&port_builder)
-        .map_err(|e| ProgramError::UnableToOpen(err_port_name, e.into()))?;
+    let mut session = Session {
+        port_name,
+        port: Some(port),
+        settings,
+        bridge,
+        control,
+        log: None,
+        rx_bytes: 0,
+        tx_bytes: 0,
+        connected_at: tokio::time::Instant::now(),
+        unsent: Vec::new(),
+    };
 
-    println!("Connected to {}", port_name);
+    println!("Connected to {}", session.port_name);
+    if let Some(addr) = &opt.listen {
+        println!("Listening for TCP connections on {}", addr);
+    }
+    if let Some(path) = &opt.control {
+        println!("Listening for commands on {}", path);
+    }
+    if !has_terminal() {
+        println!("Not reading keys, since there's no terminal. Press Control-C to exit");
+        return monitor(&mut session, &macros, &encoder, &opt).await;
+    }
     println!("Press {} to exit", exit_label(&opt));
+    println!("Press {} ? for more commands", menu_label());
+    enable_raw_mode()?;
+    if opt.mouse {
+        execute!(io::stdout(), EnableMouseCapture)?;
+    }
+    let result = monitor(&mut session, &macros, &encoder, &opt).await;
+    if opt.mouse {
+        execute!(io::stdout(), DisableMouseCapture)?;
+    }
+    disable_raw_mode()?;
+    println!();
+    result
+}
+
+// Returns whether keys can be read from a terminal. There isn't one when
+// running as a service (i.e. a bridge started with --listen).
+fn has_terminal() -> bool {
+    io::stdin().is_terminal()
+}
+
+// Monitors all of the ports which match the filtering criteria at once.
+async fn multi_main(opt: &Opt, settings: &LineSettings, config: &Config) -> Result<()> {
+    for (used, name) in [
+        (opt.listen.is_some(), "--listen"),
+        (opt.control.is_some(), "--control"),
+        (opt.line_mode, "--line-mode"),
+        (opt.status_bar, "--status-bar"),
+        (opt.forward_resize.is_some(), "--forward-resize"),
+        (opt.mouse, "--mouse"),
+        (remote_url(opt).is_some(), "a remote port"),
+    ] {
+        if used {
+            return Err(ProgramError::IncompatibleOptions(format!(
+                "{} can't be used with multiple ports",
+                name
+            )));
+        }
+    }
+    let macros = Macros::load(config, opt.profile.as_deref())?;
+    if !macros.is_empty() {
+        eprintln!("Warning: macros aren't available with multiple ports");
+    }
+    let encoder = KeyEncoder::load(opt.keymap, config, opt.profile.as_deref())?;
+    check_exit_bindings(opt, &macros, &encoder)?;
+
+    let mut ports = Vec::new();
+    for info in filtered_ports(opt)? {
+        let port = Transport::open_serial(&info.port_name, settings)?;
+        println!("Connected to {}", info.port_name);
+        ports.push((info.port_name, port));
+    }
+    println!("Press {} to exit", exit_label(opt));
+    println!("Press {} ? for more commands", menu_label());
     enable_raw_mode()?;
-    let result = monitor(&mut port, &opt).await;
+    let result = monitor_multi(ports, &encoder, opt).await;
     disable_raw_mode()?;
     println!();
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::UsbPortInfo;
+
+    fn opt(args: &[&str]) -> Opt {
+        Opt::from_iter(["serial-monitor"].iter().chain(args))
+    }
+
+    fn port(serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: "/dev/serial-monitor-test".to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x2e8a,
+                pid: 0x000a,
+                serial_number: serial_number.map(str::to_string),
+                manufacturer: Some("Raspberry Pi".to_string()),
+                product: Some("Pico".to_string()),
+                interface: None,
+            }),
+        }
+    }
+
+    fn pattern(pattern: &str) -> Option<String> {
+        Some(pattern.to_string())
+    }
+
+    #[test]
+    fn contains_and_exact() {
+        let contains = opt(&[]);
+        assert!(matches("Raspberry Pi", pattern("berry"), &contains));
+        assert!(!matches("Raspberry Pi", pattern("Cherry"), &contains));
+        assert!(matches("Raspberry Pi", None, &contains));
+
+        let exact = opt(&["--exact"]);
+        assert!(!matches("Raspberry Pi", pattern("berry"), &exact));
+        assert!(matches("Raspberry Pi", pattern("Raspberry Pi"), &exact));
+        // Wildcards are taken literally.
+        assert!(!matches("Raspberry Pi", pattern("Rasp*"), &exact));
+    }
+
+    #[test]
+    fn wildcards_and_regex() {
+        let glob = opt(&[]);
+        assert!(matches("E6614C311B4A8B2D", pattern("E66*2D"), &glob));
+        assert!(matches("E6614C311B4A8B2D", pattern("E66?4*"), &glob));
+        // With wildcards, the pattern has to match the whole string.
+        assert!(!matches("E6614C311B4A8B2D", pattern("661*"), &glob));
+        assert!(!matches("E6614C311B4A8B2D", pattern("^E66.*2D$"), &glob));
+
+        let regex = opt(&["--regex"]);
+        assert!(matches("E6614C311B4A8B2D", pattern("^E66.*2D$"), &regex));
+        assert!(matches("E6614C311B4A8B2D", pattern("4C3"), &regex));
+        assert!(!matches("E6614C311B4A8B2D", pattern("^4C3"), &regex));
+        // An invalid expression doesn't match anything.
+        assert!(!matches("E6614C311B4A8B2D", pattern("E66("), &regex));
+    }
+
+    #[test]
+    fn ignore_case() {
+        assert!(!matches("Raspberry Pi", pattern("raspberry"), &opt(&[])));
+        assert!(matches("Raspberry Pi", pattern("RASPBERRY"), &opt(&["-i"])));
+        assert!(matches("Raspberry Pi", pattern("rasp*PI"), &opt(&["-i"])));
+        assert!(matches(
+            "Raspberry Pi",
+            pattern("raspberry pi"),
+            &opt(&["-i", "--exact"])
+        ));
+        assert!(matches(
+            "Raspberry Pi",
+            pattern("^rasp"),
+            &opt(&["-i", "--regex"])
+        ));
+        assert!(!matches(
+            "Raspberry Pi",
+            pattern("^rasp"),
+            &opt(&["--regex"])
+        ));
+    }
+
+    #[test]
+    fn id_alternatives() {
+        let glob = opt(&[]);
+        assert!(id_matches(0x2e8a, &pattern("0403,2e8a"), &glob));
+        assert!(id_matches(0x2e8a, &pattern("0403, 2e8a"), &glob));
+        assert!(!id_matches(0x2e8a, &pattern("0403,10c4"), &glob));
+        assert!(id_matches(0x2e8a, &None, &glob));
+        assert!(id_matches(0x000a, &pattern("0a"), &glob));
+        assert!(!id_matches(0x000a, &pattern("0a"), &opt(&["--exact"])));
+        assert!(id_matches(0x2e8a, &pattern("2E8A"), &opt(&["-i"])));
+
+        // Regular expressions use | rather than commas.
+        let regex = opt(&["--regex"]);
+        assert!(id_matches(0x2e8a, &pattern("^(0403|2e8a)$"), &regex));
+        assert!(!id_matches(0x2e8a, &pattern("0403,2e8a"), &regex));
+
+        // Commas are only alternatives for IDs.
+        assert!(!matches(
+            "Raspberry Pi",
+            pattern("Arduino,Raspberry"),
+            &glob
+        ));
+    }
+
+    #[test]
+    fn port_filters() {
+        let matching = |args: &[&str], port: &SerialPortInfo| usb_port_matches(port, &opt(args));
+        let with_serial = port(Some("E6614C311B4A8B2D"));
+        let without_serial = port(None);
+
+        assert!(matching(
+            &["--vid", "2e8a", "--product", "Pico"],
+            &with_serial
+        ));
+        assert!(!matching(
+            &["--vid", "2e8a", "--product", "Arduino"],
+            &with_serial
+        ));
+        assert!(matching(&["--serial", "E66"], &with_serial));
+        assert!(!matching(&["--serial", "E66"], &without_serial));
+
+        assert!(!matching(&["--not-vid", "0403,2e8a"], &with_serial));
+        assert!(matching(&["--not-vid", "0403"], &with_serial));
+        assert!(!matching(&["--not-serial", "E66"], &with_serial));
+        assert!(!matching(
+            &["--not-manufacturer", "raspberry", "-i"],
+            &with_serial
+        ));
+        // Exclusions never match a missing value, even one which would
+        // match anything.
+        assert!(matching(&["--not-serial", "E66"], &without_serial));
+        assert!(matching(&["--not-serial", "*"], &without_serial));
+        assert!(matching(
+            &["--not-serial", ".*", "--regex"],
+            &without_serial
+        ));
+        assert!(matching(&["--not-interface", "0"], &with_serial));
+    }
+
+    #[test]
+    fn regex_errors() {
+        let error = |args: &[&str]| check_filters(&opt(args)).unwrap_err().to_string();
+        assert!(check_filters(&opt(&["--serial", "E66("])).is_ok());
+        assert!(check_filters(&opt(&["--regex", "--serial", "^E66"])).is_ok());
+        assert!(error(&["--regex", "--serial", "E66("]).starts_with("Invalid filter 'E66(': "));
+        assert!(error(&["--regex", "--not-chip", "["]).starts_with("Invalid filter '[': "));
+    }
+
+    #[test]
+    fn interface_errors() {
+        let error = |args: &[&str]| check_filters(&opt(args)).unwrap_err().to_string();
+        assert!(check_filters(&opt(&["--interface", "0, 2"])).is_ok());
+        assert_eq!(
+            error(&["--interface", "0,x"]),
+            "Invalid filter '0,x': expecting interface numbers"
+        );
+        assert_eq!(
+            error(&["--not-interface", "one"]),
+            "Invalid filter 'one': expecting interface numbers"
+        );
+    }
+}
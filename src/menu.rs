@@ -0,0 +1,67 @@
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+
+// The command menu is reached by pressing Control-T followed by another key.
+pub const MENU_CHAR: char = 't';
+
+/// Actions which can be selected from the command menu.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MenuAction {
+    /// Release the port (or reconnect to it if it's already released).
+    Release,
+    /// Show the available commands.
+    Help,
+    /// Pick a line which was sent before, and send it again.
+    History,
+    /// Freeze the output, to scroll back through it.
+    Scrollback,
+    /// Move the keyboard focus to the next port (multiple ports only).
+    NextPort,
+    /// Move the keyboard focus to the port with this index (multiple ports only).
+    SelectPort(usize),
+    /// Send the menu key itself.
+    SendMenuKey,
+}
+
+// Returns the Event::Key variant of the key which starts a menu command.
+pub fn menu_code() -> Event {
+    Event::Key(KeyEvent::new(
+        KeyCode::Char(MENU_CHAR),
+        KeyModifiers::CONTROL,
+    ))
+}
+
+// Returns a human readable string of the menu key.
+pub fn menu_label() -> String {
+    format!("Control-{}", MENU_CHAR.to_ascii_uppercase())
+}
+
+// Returns the action for the key pressed after the menu key. The Control
+// modifier is ignored, so that it doesn't matter whether it's still held down.
+pub fn menu_action(key_event: &KeyEvent) -> Option<MenuAction> {
+    match key_event.code {
+        KeyCode::Char(MENU_CHAR) => Some(MenuAction::SendMenuKey),
+        KeyCode::Char('r') => Some(MenuAction::Release),
+        KeyCode::Char('p') | KeyCode::Up => Some(MenuAction::History),
+        KeyCode::Char('s') | KeyCode::PageUp => Some(MenuAction::Scrollback),
+        KeyCode::Char('?') | KeyCode::Char('h') => Some(MenuAction::Help),
+        KeyCode::Char('n') => Some(MenuAction::NextPort),
+        KeyCode::Char(ch @ '1'..='9') => Some(MenuAction::SelectPort(ch as usize - '1' as usize)),
+        _ => None,
+    }
+}
+
+// Returns the help text for the command menu.
+pub fn menu_help() -> String {
+    let label = menu_label();
+    format!(
+        "{label} r  Release the port (or reconnect to it)\r\n\
+         {label} p  Pick a previously sent line (Up/Down, Control-R to search) and resend it\r\n\
+         {label} s  Freeze the output and scroll back through it (single port only)\r\n\
+         {label} n  Switch keyboard focus to the next port (multiple ports only)\r\n\
+         {label} 1-9  Switch keyboard focus to port 1-9 (multiple ports only)\r\n\
+         {label} ?  Show this help\r\n\
+         {label} {menu}  Send {label}\r\n",
+        label = label,
+        menu = MENU_CHAR,
+    )
+}
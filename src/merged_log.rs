@@ -0,0 +1,237 @@
+use crate::error::{ProgramError, Result};
+use crate::log::SessionLog;
+use crate::multi::{short_name, spawn_port, PortEvent};
+use crate::string_decoder::StringDecoder;
+use crate::transport::Transport;
+use crate::Opt;
+use futures::{future::FutureExt, pin_mut, select};
+use mio_serial::SerialPortInfo;
+use serialport::SerialPortType;
+use std::io::Write;
+use tokio::sync::mpsc;
+use tokio_util::bytes::{Bytes, BytesMut};
+use tokio_util::codec::Decoder;
+
+/// What to tag each line of a merged log with.
+#[derive(Clone, Copy, Debug, strum::EnumString, strum::VariantNames)]
+#[strum(serialize_all = "snake_case")]
+pub enum LogTag {
+    /// The name of the port (i.e. ttyACM0).
+    Port,
+    /// The USB serial number (or the name of the port if there isn't one).
+    Serial,
+}
+
+// Returns the tag used for lines from port.
+fn port_tag(info: &SerialPortInfo, tag: LogTag) -> String {
+    if let (LogTag::Serial, SerialPortType::UsbPort(usb_info)) = (tag, &info.port_type) {
+        if let Some(serial) = &usb_info.serial_number {
+            return serial.clone();
+        }
+    }
+    short_name(&info.port_name)
+}
+
+// Returns the current local time, formatted for the start of a log line.
+pub fn timestamp() -> String {
+    chrono::Local::now()
+        .format("%Y-%m-%d %H:%M:%S%.3f")
+        .to_string()
+}
+
+// Where the merged log is written.
+enum LogOutput {
+    Stdout,
+    File(SessionLog),
+}
+
+impl LogOutput {
+    fn write(&mut self, text: &str) -> Result<()> {
+        match self {
+            LogOutput::Stdout => {
+                print!("{}", text);
+                std::io::stdout().flush()?;
+            }
+            LogOutput::File(log) => log.write(text.as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+/// Collects the text received from a port into whole lines, so that lines
+/// from different ports are never mixed up in the log.
+struct LineCollector {
+    /// The unfinished line, starting with its prefix.
+    line: String,
+}
+
+impl LineCollector {
+    const fn new() -> LineCollector {
+        LineCollector {
+            line: String::new(),
+        }
+    }
+
+    // Adds text, returning the lines which it finishes. prefix is called when
+    // the first character of each line arrives.
+    fn collect(&mut self, text: &str, prefix: &dyn Fn() -> String) -> String {
+        let mut lines = String::new();
+        for ch in text.chars() {
+            if self.line.is_empty() {
+                self.line = prefix();
+            }
+            self.line.push(ch);
+            if ch == '\n' {
+                lines.push_str(&self.line);
+                self.line.clear();
+            }
+        }
+        lines
+    }
+
+    // Returns the unfinished line (if any), ended with a newline.
+    fn finish(&mut self) -> String {
+        if self.line.is_empty() {
+            String::new()
+        } else {
+            let mut line = std::mem::take(&mut self.line);
+            line.push('\n');
+            line
+        }
+    }
+}
+
+struct LogPort {
+    tag: String,
+    lines: LineCollector,
+    /// Nothing is ever written to the port, but the port's task stops when
+    /// this is dropped.
+    _writer: mpsc::UnboundedSender<Bytes>,
+    decoder: StringDecoder,
+    data: BytesMut,
+}
+
+enum LogEvent {
+    Port(Option<PortEvent>),
+    Interrupted,
+}
+
+// Opens every port which matches the filtering criteria, and writes the data
+// received from all of them to a single log (or stdout if path is "-"). Each
+// line is timestamped and tagged with the port that it came from. This runs
+// until all of the ports are closed, or Control-C is pressed.
+pub async fn merged_log(path: &str, ports: Vec<SerialPortInfo>, opt: &Opt) -> Result<()> {
+    if opt.listen.is_some() || opt.control.is_some() {
+        return Err(ProgramError::IncompatibleOptions(
+            "--merged-log can't be used with --listen or --control".to_string(),
+        ));
+    }
+    let mut output = if path == "-" {
+        LogOutput::Stdout
+    } else {
+        LogOutput::File(SessionLog::create(path)?)
+    };
+    let settings = crate::line_settings(opt)?;
+    let (event_writer, mut port_events) = mpsc::unbounded_channel();
+    let mut log_ports = Vec::new();
+
+    for (index, info) in ports.iter().enumerate() {
+        let port = Transport::open_serial(&info.port_name, &settings)?;
+        let tag = port_tag(info, opt.tag);
+        eprintln!("Logging {} as [{}]", info.port_name, tag);
+        log_ports.push(LogPort {
+            tag,
+            lines: LineCollector::new(),
+            _writer: spawn_port(index, port, event_writer.clone()),
+            decoder: StringDecoder::new(),
+            data: BytesMut::new(),
+        });
+    }
+    drop(event_writer);
+
+    // Control-C is only listened for once, so that a press between two port
+    // events isn't missed.
+    let ctrl_c = tokio::signal::ctrl_c().fuse();
+    pin_mut!(ctrl_c);
+
+    loop {
+        let log_event = {
+            let port_event = port_events.recv().fuse();
+            pin_mut!(port_event);
+
+            select! {
+                maybe_port = port_event => LogEvent::Port(maybe_port),
+                _ = ctrl_c => LogEvent::Interrupted,
+            }
+        };
+
+        match log_event {
+            LogEvent::Port(Some(PortEvent::Data(index, data))) => {
+                let port = &mut log_ports[index];
+                port.data.extend_from_slice(&data);
+                let mut text = String::new();
+                while !port.data.is_empty() {
+                    if let Some(decoded) = port.decoder.decode(&mut port.data)? {
+                        text.push_str(&decoded);
+                    }
+                }
+                // Lines are terminated by LF alone in the log.
+                let prefix = format!("[{}] ", port.tag);
+                let text = text.replace('\r', "");
+                output.write(
+                    &port
+                        .lines
+                        .collect(&text, &|| format!("{} {}", timestamp(), prefix)),
+                )?;
+            }
+            LogEvent::Port(Some(PortEvent::Closed(index, err))) => {
+                let port = &mut log_ports[index];
+                let reason = match err {
+                    Some(e) => format!("Serial Error: {:?}", e),
+                    None => "Port closed".to_string(),
+                };
+                let prefix = format!("[{}] ", port.tag);
+                let mut text = port.lines.finish();
+                text.push_str(&port.lines.collect(&format!("{}\n", reason), &|| {
+                    format!("{} {}", timestamp(), prefix)
+                }));
+                output.write(&text)?;
+            }
+            LogEvent::Port(None) | LogEvent::Interrupted => break,
+        }
+    }
+
+    for port in log_ports.iter_mut() {
+        output.write(&port.lines.finish())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaved_partial_reads() {
+        let count = std::cell::Cell::new(0);
+        let prefix = |tag: &str| {
+            count.set(count.get() + 1);
+            format!("{} [{}] ", count.get(), tag)
+        };
+        let mut a = LineCollector::new();
+        let mut b = LineCollector::new();
+
+        assert_eq!(a.collect("boot", &|| prefix("a")), "");
+        assert_eq!(b.collect("hel", &|| prefix("b")), "");
+        assert_eq!(a.collect("ing\nre", &|| prefix("a")), "1 [a] booting\n");
+        assert_eq!(b.collect("lo\n", &|| prefix("b")), "2 [b] hello\n");
+        assert_eq!(
+            a.collect("ady\n\n", &|| prefix("a")),
+            "3 [a] ready\n4 [a] \n"
+        );
+        assert_eq!(b.collect("bye", &|| prefix("b")), "");
+        assert_eq!(a.finish(), "");
+        assert_eq!(b.finish(), "5 [b] bye\n");
+        assert_eq!(b.finish(), "");
+    }
+}
@@ -0,0 +1,607 @@
+use crate::bridge::{BridgeEvent, TcpBridge};
+use crate::control::{ControlCommand, ControlRequest, ControlServer};
+use crate::error::{ProgramError, Result};
+use crate::history::History;
+use crate::key_encoder::{control_char, encode_mouse, KeyEncoder};
+use crate::keys::{ExitCheck, ExitKey};
+use crate::line_editor::{EditResult, LineEditor};
+use crate::log::SessionLog;
+use crate::macros::{MacroPlayer, Macros};
+use crate::menu::{menu_action, menu_code, menu_help, menu_label, MenuAction, MENU_CHAR};
+use crate::resize::ResizeForwarder;
+use crate::scrollback::Scrollback;
+use crate::status_bar::StatusBar;
+use crate::string_decoder::StringDecoder;
+use crate::transport::{LineCommand, LineSettings, PortIo, Transport};
+use crate::{exit_key, exit_label, handle_key_event, has_terminal, hex_str, history_name, Opt};
+use crossterm::event::{Event, EventStream, KeyEventKind};
+use futures::{future::FutureExt, pin_mut, select, StreamExt};
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+/// Everything that monitor needs to know about a connection.
+pub struct Session {
+    /// Name of the port (or URL) that we're connected to.
+    pub port_name: String,
+    /// The open port, or None if it has been released.
+    pub port: Option<Transport>,
+    pub settings: LineSettings,
+    pub bridge: Option<TcpBridge>,
+    pub control: Option<ControlServer>,
+    pub log: Option<SessionLog>,
+    /// Number of bytes received from the port.
+    pub rx_bytes: u64,
+    /// Number of bytes sent to the port.
+    pub tx_bytes: u64,
+    /// When the port was last opened.
+    pub connected_at: Instant,
+    /// Data waiting to be written to the port.
+    pub unsent: Vec<u8>,
+}
+
+impl Session {
+    // Queues data to be written to the port. It's written while waiting for
+    // other events, so a port which isn't taking data (i.e. because of flow
+    // control) doesn't hold up the terminal.
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        if self.port.is_none() {
+            return Err(ProgramError::PortReleased);
+        }
+        self.unsent.extend_from_slice(data);
+        Ok(())
+    }
+
+    // Closes the port so that other programs (like flashing tools) can use it.
+    fn release(&mut self) -> Result<()> {
+        if self.port.take().is_none() {
+            return Err(ProgramError::PortReleased);
+        }
+        self.unsent.clear();
+        println!(
+            "\r\nPort released, press any key (or {} r) to reconnect\r",
+            menu_label()
+        );
+        Ok(())
+    }
+
+    // Reopens a released port using the current settings.
+    async fn reacquire(&mut self) -> Result<()> {
+        if self.port.is_none() {
+            self.port = Some(Transport::open(&self.port_name, &self.settings).await?);
+            self.connected_at = Instant::now();
+            // Reopening the port ends any break.
+            self.settings.break_on = false;
+            println!("\r\nReconnected to {}\r", self.port_name);
+        }
+        Ok(())
+    }
+
+    // Changes the line settings of the port, and lets the TCP bridge know
+    // about the new settings. Changes made while the port is released are
+    // used when it's reopened.
+    fn apply(&mut self, cmd: &LineCommand) -> Result<()> {
+        if let Some(port) = &mut self.port {
+            port.apply(cmd)?;
+        }
+        self.settings.apply(cmd);
+        if let Some(bridge) = &self.bridge {
+            bridge.update_settings(&self.settings);
+        }
+        Ok(())
+    }
+
+    // Returns a human readable description of the state of the session.
+    fn status(&self) -> String {
+        let on_off = |state: Option<bool>| match state {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "unknown",
+        };
+        format!(
+            "port: {}{}\nsettings: {}\nflow: {:?}\ndtr: {}\nrts: {}\nlog: {}\n",
+            self.port_name,
+            if self.port.is_none() {
+                " (released)"
+            } else {
+                ""
+            },
+            self.settings,
+            self.settings.flow_control,
+            on_off(self.settings.dtr),
+            on_off(self.settings.rts),
+            self.log.as_ref().map_or("off", |log| log.path()),
+        )
+    }
+
+    // Carries out a command received on the control socket, returning the
+    // text to send back.
+    async fn control(&mut self, command: ControlCommand) -> Result<String> {
+        match command {
+            ControlCommand::Send(data) => self.send(&data)?,
+            ControlCommand::Line(cmd) => self.apply(&cmd)?,
+            ControlCommand::ToggleDtr => {
+                self.apply(&LineCommand::Dtr(!self.settings.dtr.unwrap_or(true)))?
+            }
+            ControlCommand::ToggleRts => {
+                self.apply(&LineCommand::Rts(!self.settings.rts.unwrap_or(true)))?
+            }
+            ControlCommand::LogStart(path) => self.log = Some(SessionLog::create(&path)?),
+            ControlCommand::LogStop => self.log = None,
+            ControlCommand::Status => return Ok(self.status()),
+            ControlCommand::Release => self.release()?,
+            ControlCommand::Reacquire => self.reacquire().await?,
+        }
+        Ok(String::new())
+    }
+}
+
+// Events which the main loop in monitor reacts to.
+enum MonitorEvent {
+    Terminal(Option<io::Result<Event>>),
+    Serial(io::Result<PortIo>),
+    Bridge(Option<BridgeEvent>),
+    Control(Option<ControlRequest>),
+    /// Time to check whether a released port has reappeared.
+    ReleasedTick,
+    /// A delay in a macro has finished.
+    MacroTick,
+    /// The size of the terminal has settled, and can be sent to the device.
+    ResizeTick,
+    /// The exit key wasn't pressed again in time, so it's sent to the device.
+    ExitTick,
+    /// Time to bring the status line up to date.
+    StatusTick,
+    /// Control-C was pressed when running without a terminal.
+    Interrupted,
+}
+
+// Waits for an event from the terminal, or forever if there isn't one.
+async fn terminal_event(reader: &mut Option<EventStream>) -> Option<io::Result<Event>> {
+    match reader {
+        Some(reader) => reader.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+// Waits for Control-C when running without a terminal (with one, it's just a
+// key which is sent to the port), otherwise forever.
+async fn interrupted(headless: bool) {
+    if headless {
+        let _ = tokio::signal::ctrl_c().await;
+    } else {
+        futures::future::pending().await
+    }
+}
+
+// Waits for a request from a TCP client, or forever if there is no TCP bridge.
+async fn bridge_recv(bridge: &mut Option<TcpBridge>) -> Option<BridgeEvent> {
+    match bridge {
+        Some(bridge) => bridge.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+// Waits for a command on the control socket, or forever if there isn't one.
+async fn control_recv(control: &mut Option<ControlServer>) -> Option<ControlRequest> {
+    match control {
+        Some(control) => control.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+// Reads data from the port while writing any unsent data to it, or waits
+// forever if the port is released.
+async fn port_io(
+    port: &mut Option<Transport>,
+    buf: &mut [u8],
+    unsent: &[u8],
+) -> io::Result<PortIo> {
+    match port {
+        Some(port) => port.read_or_write(buf, unsent).await,
+        None => futures::future::pending().await,
+    }
+}
+
+// Waits a while if watching for a released port to reappear, otherwise forever.
+async fn released_tick(watching: bool) {
+    if watching {
+        tokio::time::sleep(Duration::from_millis(500)).await
+    } else {
+        futures::future::pending().await
+    }
+}
+
+// Returns what needs to be printed to show a message on a line of its own,
+// along with the output from the port. While the view is frozen, the message
+// is shown once it resumes.
+fn show_message(message: &str, editor: &mut LineEditor, scrollback: &mut Scrollback) -> String {
+    if scrollback.hold_message(message) {
+        return String::new();
+    }
+    let start = if editor.at_line_start() { "" } else { "\r\n" };
+    editor.output(&format!("{}{}\r\n", start, message))
+}
+
+// Sends the data from macros until the next delay (or the end of the macros).
+fn play_macros(session: &mut Session, player: &mut MacroPlayer, opt: &Opt) -> Result<()> {
+    while let Some(data) = player.next_data() {
+        if opt.debug {
+            println!("Macro: {}\r", hex_str(&data));
+        }
+        match session.send(&data) {
+            // The rest of the macro is thrown away if the port is released.
+            Err(ProgramError::PortReleased) => *player = MacroPlayer::default(),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+// Main function which collects input from the user and sends it over the serial link
+// and collects serial data and presents it to the user.
+pub async fn monitor(
+    session: &mut Session,
+    macros: &Macros,
+    encoder: &KeyEncoder,
+    opt: &Opt,
+) -> Result<()> {
+    // Without a terminal (i.e. when running as a service with --listen),
+    // there are no keys to read.
+    let mut reader = has_terminal().then(EventStream::new);
+    let interrupt = interrupted(reader.is_none()).fuse();
+    pin_mut!(interrupt);
+    let mut decoder = StringDecoder::new();
+    let mut serial_buf = [0u8; 1024];
+    let mut serial_data = BytesMut::new();
+
+    let mut exit = ExitKey::new(exit_key(opt), opt.exit_double);
+    let menu_code = menu_code();
+    let mut menu_pending = false;
+    // Set once a released port has been seen to disappear (which is what
+    // usually happens when a device is being flashed).
+    let mut port_vanished = false;
+    let history = History::load(&history_name(opt, &session.port_name));
+    let mut editor = LineEditor::new(history, opt.line_mode);
+    let mut player = MacroPlayer::default();
+    let mut resizer = ResizeForwarder::new(opt.forward_resize);
+    // The status bar is declared first so that it's dropped last, once the
+    // frozen view has been left.
+    let mut status_bar = StatusBar::new(opt.status_bar);
+    let mut scrollback = Scrollback::new(opt.scrollback);
+    let exit_label = exit_label(opt);
+    print!(
+        "{}{}",
+        status_bar.setup(),
+        status_bar.draw(session, &exit_label)
+    );
+    std::io::stdout().flush()?;
+    // Start off by telling the device the current size (some terminals
+    // report 0 when they don't know it).
+    if let Ok((cols, rows)) = crossterm::terminal::size() {
+        if cols > 0 && rows > 0 {
+            resizer.resized(cols, status_bar.device_rows(rows));
+        }
+    }
+
+    loop {
+        let monitor_event = {
+            let event = terminal_event(&mut reader).fuse();
+            // Only local ports can be watched for reappearing.
+            let watching = session.port.is_none() && !session.port_name.contains("://");
+            let tick_event = released_tick(watching).fuse();
+            let serial_event = port_io(&mut session.port, &mut serial_buf, &session.unsent).fuse();
+            let bridge_event = bridge_recv(&mut session.bridge).fuse();
+            let control_event = control_recv(&mut session.control).fuse();
+            let macro_event = player.wait().fuse();
+            let resize_event = resizer.wait().fuse();
+            let status_event = status_bar.wait().fuse();
+            let exit_event = exit.wait().fuse();
+            pin_mut!(
+                event,
+                serial_event,
+                bridge_event,
+                control_event,
+                tick_event,
+                macro_event,
+                resize_event,
+                status_event,
+                exit_event
+            );
+
+            select! {
+                maybe_event = event => MonitorEvent::Terminal(maybe_event),
+                maybe_serial = serial_event => MonitorEvent::Serial(maybe_serial),
+                maybe_bridge = bridge_event => MonitorEvent::Bridge(maybe_bridge),
+                maybe_control = control_event => MonitorEvent::Control(maybe_control),
+                _ = tick_event => MonitorEvent::ReleasedTick,
+                _ = macro_event => MonitorEvent::MacroTick,
+                _ = resize_event => MonitorEvent::ResizeTick,
+                _ = status_event => MonitorEvent::StatusTick,
+                _ = exit_event => MonitorEvent::ExitTick,
+                _ = interrupt => MonitorEvent::Interrupted,
+            }
+        };
+
+        match monitor_event {
+            MonitorEvent::Terminal(maybe_event) => match maybe_event {
+                Some(Ok(event)) => {
+                    if let Event::Key(key_event) = event {
+                        // Some terminals (i.e. on Windows) report keys being
+                        // released as well as pressed.
+                        if key_event.kind == KeyEventKind::Release {
+                            continue;
+                        }
+                        match exit.check(&key_event) {
+                            ExitCheck::Exit => break,
+                            ExitCheck::Wait => continue,
+                            ExitCheck::Pass(Some(held)) => {
+                                if let Some(key) = handle_key_event(held, encoder, opt)? {
+                                    match session.send(&key) {
+                                        Err(ProgramError::PortReleased) => {}
+                                        result => result?,
+                                    }
+                                }
+                            }
+                            ExitCheck::Pass(None) => {}
+                        }
+                        if scrollback.is_frozen() {
+                            print!("{}", scrollback.handle_key(&key_event));
+                            if !scrollback.is_frozen() {
+                                let held = scrollback.take_held();
+                                print!("{}", editor.output(&held));
+                                status_bar.output(&held);
+                                // The status line isn't on the frozen view's screen.
+                                print!(
+                                    "{}{}",
+                                    status_bar.setup(),
+                                    status_bar.draw(session, &exit_label)
+                                );
+                            }
+                            std::io::stdout().flush()?;
+                            continue;
+                        }
+                        if menu_pending {
+                            menu_pending = false;
+                            match menu_action(&key_event) {
+                                Some(MenuAction::SendMenuKey) => {
+                                    if let Some(byte) = control_char(MENU_CHAR) {
+                                        match session.send(&[byte]) {
+                                            Err(ProgramError::PortReleased) => {}
+                                            result => result?,
+                                        }
+                                    }
+                                    continue;
+                                }
+                                Some(MenuAction::Scrollback) => {
+                                    if opt.scrollback > 0 {
+                                        print!("{}", scrollback.freeze());
+                                    } else {
+                                        print!("\r\nThe scrollback buffer is turned off\r\n");
+                                    }
+                                    std::io::stdout().flush()?;
+                                    continue;
+                                }
+                                Some(MenuAction::History) => {
+                                    if editor.open_history() {
+                                        print!("{}", editor.render());
+                                    } else {
+                                        print!("\r\nNo lines have been sent yet\r\n");
+                                    }
+                                    std::io::stdout().flush()?;
+                                    continue;
+                                }
+                                Some(MenuAction::Release) => {
+                                    if session.port.is_some() {
+                                        session.release()?;
+                                        port_vanished = false;
+                                    } else if let Err(e) = session.reacquire().await {
+                                        println!("\r\n{}\r", e);
+                                    }
+                                    continue;
+                                }
+                                _ => {
+                                    print!("\r\n{}", menu_help());
+                                    std::io::stdout().flush()?;
+                                    continue;
+                                }
+                            }
+                        } else if event == menu_code {
+                            menu_pending = true;
+                            continue;
+                        }
+                        if session.port.is_none() {
+                            if let Err(e) = session.reacquire().await {
+                                println!("\r\n{}\r", e);
+                            }
+                        } else if let Some(steps) = macros.find(&key_event) {
+                            player.start(steps);
+                            play_macros(session, &mut player, opt)?;
+                        } else if editor.is_open() {
+                            match editor.handle_key(&key_event) {
+                                EditResult::Handled => print!("{}", editor.render()),
+                                EditResult::Send(line) => {
+                                    // Take the line off the screen, since the
+                                    // device normally echoes it.
+                                    print!("{}", editor.render());
+                                    if opt.echo {
+                                        print!("{}", editor.output(&format!("{}\r\n", line)));
+                                    }
+                                    let mut data = line.into_bytes();
+                                    data.extend_from_slice(opt.enter.bytes());
+                                    session.send(&data)?;
+                                }
+                                EditResult::NotHandled => {
+                                    if let Some(key) = handle_key_event(key_event, encoder, opt)? {
+                                        session.send(&key)?;
+                                    }
+                                }
+                            }
+                            std::io::stdout().flush()?;
+                        } else {
+                            if opt.record_typed {
+                                editor.record_key(&key_event);
+                            }
+                            if let Some(key) = handle_key_event(key_event, encoder, opt)? {
+                                session.send(&key)?;
+                            }
+                        }
+                    } else if let Event::Resize(cols, rows) = event {
+                        resizer.resized(cols, status_bar.device_rows(rows));
+                        if scrollback.is_frozen() {
+                            print!("{}", scrollback.draw());
+                        } else {
+                            print!(
+                                "{}{}",
+                                status_bar.setup(),
+                                status_bar.draw(session, &exit_label)
+                            );
+                        }
+                        std::io::stdout().flush()?;
+                    } else if let Event::Mouse(mouse_event) = event {
+                        // Without --mouse, these only show up if something
+                        // on the device turned on mouse reporting. Clicks on
+                        // the frozen view aren't meant for the device.
+                        let wanted = opt.mouse && !scrollback.is_frozen();
+                        if let Some(data) = encode_mouse(&mouse_event).filter(|_| wanted) {
+                            match session.send(&data) {
+                                Err(ProgramError::PortReleased) => {}
+                                result => result?,
+                            }
+                        }
+                    } else if opt.debug {
+                        println!("Unrecognized Event::{:?}\r", event);
+                    }
+                }
+                Some(Err(e)) => println!("crossterm Error: {:?}\r", e),
+                None => {
+                    println!("maybe_event returned None\r");
+                }
+            },
+            MonitorEvent::Serial(maybe_serial) => match maybe_serial {
+                Ok(PortIo::Read(0)) => {
+                    println!("maybe_serial returned None\r");
+                    break;
+                }
+                Ok(PortIo::Written(n)) => {
+                    session.unsent.drain(..n);
+                    session.tx_bytes += n as u64;
+                }
+                Ok(PortIo::Read(n)) => {
+                    session.rx_bytes += n as u64;
+                    if let Some(bridge) = &session.bridge {
+                        bridge.send(&serial_buf[..n]);
+                    }
+                    if let Some(log) = &mut session.log {
+                        log.write(&serial_buf[..n])?;
+                    }
+                    serial_data.extend_from_slice(&serial_buf[..n]);
+                    // The decoder returns None when it consumes part of a
+                    // character, so keep going until all of the data is used.
+                    while !serial_data.is_empty() {
+                        if let Some(serial_event) = decoder.decode(&mut serial_data)? {
+                            // While the view is frozen, the output is shown
+                            // once it resumes.
+                            scrollback.push(&serial_event);
+                            if !scrollback.is_frozen() {
+                                if opt.debug {
+                                    println!("Serial Event:{:?}\r", serial_event);
+                                } else {
+                                    print!("{}", editor.output(&serial_event));
+                                    status_bar.output(&serial_event);
+                                    print!("{}", status_bar.catch_up(session, &exit_label));
+                                    std::io::stdout().flush()?;
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("Serial Error: {:?}\r", e);
+                    // This most likely means that the serial port has been unplugged.
+                    break;
+                }
+            },
+            MonitorEvent::Bridge(maybe_bridge) => match maybe_bridge {
+                Some(BridgeEvent::Data(data)) => match session.send(&data) {
+                    // Data from TCP clients is discarded while the port is released.
+                    Err(ProgramError::PortReleased) => {}
+                    result => result?,
+                },
+                Some(BridgeEvent::Line(cmd, reply)) => {
+                    if opt.debug {
+                        println!("Line Command: {:?}\r", cmd);
+                    }
+                    let result = session.apply(&cmd);
+                    if let Err(e) = &result {
+                        let message = format!("Unable to apply {:?}: {:?}", cmd, e);
+                        print!("{}", show_message(&message, &mut editor, &mut scrollback));
+                        std::io::stdout().flush()?;
+                    }
+                    // The client may have gone away, which is fine.
+                    let _ = reply.send(result.is_ok());
+                }
+                Some(BridgeEvent::Message(message)) => {
+                    print!("{}", show_message(&message, &mut editor, &mut scrollback));
+                    std::io::stdout().flush()?;
+                }
+                None => {}
+            },
+            MonitorEvent::Control(maybe_control) => {
+                if let Some(request) = maybe_control {
+                    if opt.debug {
+                        println!("Control Command: {:?}\r", request.command);
+                    }
+                    let result = session.control(request.command).await;
+                    // The client may have gone away, which is fine.
+                    let _ = request.reply.send(result.map_err(|e| e.to_string()));
+                }
+            }
+            MonitorEvent::StatusTick => {
+                status_bar.update(session);
+                if !scrollback.is_frozen() {
+                    print!("{}", status_bar.draw(session, &exit_label));
+                    std::io::stdout().flush()?;
+                }
+            }
+            MonitorEvent::ResizeTick => {
+                if let Some(data) = resizer.take(opt.enter.bytes()) {
+                    match session.send(&data) {
+                        // The size is forgotten while the port is released.
+                        Err(ProgramError::PortReleased) => {}
+                        result => result?,
+                    }
+                }
+            }
+            MonitorEvent::Interrupted => break,
+            MonitorEvent::ExitTick => {
+                if let Some(held) = exit.take_held() {
+                    if let Some(key) = handle_key_event(held, encoder, opt)? {
+                        match session.send(&key) {
+                            Err(ProgramError::PortReleased) => {}
+                            result => result?,
+                        }
+                    }
+                }
+            }
+            MonitorEvent::MacroTick => play_macros(session, &mut player, opt)?,
+            MonitorEvent::ReleasedTick => {
+                if !std::path::Path::new(&session.port_name).exists() {
+                    port_vanished = true;
+                } else if port_vanished && session.reacquire().await.is_ok() {
+                    port_vanished = false;
+                }
+            }
+        }
+    }
+
+    // Put the screen back if exiting while the view is frozen.
+    if scrollback.is_frozen() {
+        print!("{}", scrollback.resume());
+        print!("{}", editor.output(&scrollback.take_held()));
+    }
+    Ok(())
+}
@@ -0,0 +1,318 @@
+use crate::error::Result;
+use crate::key_encoder::{control_char, KeyEncoder};
+use crate::keys::{ExitCheck, ExitKey};
+use crate::menu::{menu_action, menu_code, menu_help, MenuAction, MENU_CHAR};
+use crate::string_decoder::StringDecoder;
+use crate::transport::Transport;
+use crate::{exit_key, handle_key_event, Opt};
+use crossterm::event::{Event, EventStream, KeyEventKind};
+use crossterm::style::{Color, Stylize};
+use futures::{future::FutureExt, pin_mut, select, StreamExt};
+use std::io;
+use std::io::Write;
+use tokio::sync::mpsc;
+use tokio_util::bytes::{Bytes, BytesMut};
+use tokio_util::codec::Decoder;
+
+// Colors used for the port name prefixes, in the order that ports are opened.
+const PORT_COLORS: [Color; 6] = [
+    Color::Green,
+    Color::Cyan,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+];
+
+/// Things which happen on one of the ports of a multi-port session.
+pub enum PortEvent {
+    /// Data was read from the port with the given index.
+    Data(usize, Bytes),
+    /// The port with the given index was closed (or had an error).
+    Closed(usize, Option<io::Error>),
+}
+
+enum PortTaskEvent {
+    Read(io::Result<usize>),
+    Write(Option<Bytes>),
+}
+
+// Runs a port in its own task. Data read from the port is sent to events,
+// and the returned channel is used to write data to the port.
+pub fn spawn_port(
+    index: usize,
+    mut port: Transport,
+    events: mpsc::UnboundedSender<PortEvent>,
+) -> mpsc::UnboundedSender<Bytes> {
+    let (writer, mut writes) = mpsc::unbounded_channel::<Bytes>();
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        loop {
+            let task_event = {
+                let read_event = port.read(&mut buf).fuse();
+                let write_event = writes.recv().fuse();
+                pin_mut!(read_event, write_event);
+
+                select! {
+                    maybe_read = read_event => PortTaskEvent::Read(maybe_read),
+                    maybe_write = write_event => PortTaskEvent::Write(maybe_write),
+                }
+            };
+
+            let closed = match task_event {
+                PortTaskEvent::Read(Ok(0)) => Some(None),
+                PortTaskEvent::Read(Ok(n)) => {
+                    let data = Bytes::copy_from_slice(&buf[..n]);
+                    if events.send(PortEvent::Data(index, data)).is_err() {
+                        break;
+                    }
+                    None
+                }
+                PortTaskEvent::Read(Err(e)) => Some(Some(e)),
+                PortTaskEvent::Write(Some(data)) => port.write_all(&data).await.err().map(Some),
+                PortTaskEvent::Write(None) => break,
+            };
+            if let Some(err) = closed {
+                let _ = events.send(PortEvent::Closed(index, err));
+                break;
+            }
+        }
+    });
+
+    writer
+}
+
+// Returns a short name for a port, used to tag its output.
+pub fn short_name(port_name: &str) -> String {
+    match port_name.rsplit(['/', '\\']).next() {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => port_name.to_string(),
+    }
+}
+
+/// Interleaves text from several sources so that each line starts with a
+/// prefix identifying where it came from. A line which is interrupted by
+/// another source is ended early.
+pub struct LineMerger {
+    /// Used to end a line which was interrupted.
+    eol: &'static str,
+    /// The source which wrote last.
+    last: Option<usize>,
+    at_line_start: bool,
+}
+
+impl LineMerger {
+    pub const fn new(eol: &'static str) -> LineMerger {
+        LineMerger {
+            eol,
+            last: None,
+            at_line_start: true,
+        }
+    }
+
+    // Returns text with prefixes inserted at the start of each line. prefix
+    // is called once for every line started.
+    pub fn merge(&mut self, source: usize, text: &str, prefix: &dyn Fn() -> String) -> String {
+        let mut output = String::with_capacity(text.len());
+        if self.last != Some(source) && !self.at_line_start {
+            output.push_str(self.eol);
+            self.at_line_start = true;
+        }
+        self.last = Some(source);
+        for ch in text.chars() {
+            if self.at_line_start {
+                output.push_str(&prefix());
+                self.at_line_start = false;
+            }
+            output.push(ch);
+            if ch == '\n' {
+                self.at_line_start = true;
+            }
+        }
+        output
+    }
+
+    // Returns what's needed to end the current line, if it's unfinished.
+    pub fn finish(&mut self) -> &'static str {
+        if self.at_line_start {
+            ""
+        } else {
+            self.at_line_start = true;
+            self.eol
+        }
+    }
+}
+
+// A port which is being monitored in a multi-port session.
+struct MultiPort {
+    name: String,
+    prefix: String,
+    writer: mpsc::UnboundedSender<Bytes>,
+    decoder: StringDecoder,
+    data: BytesMut,
+    open: bool,
+}
+
+enum MultiEvent {
+    Terminal(Option<io::Result<Event>>),
+    Port(Option<PortEvent>),
+    /// The exit key wasn't pressed again in time, so it's sent to the port.
+    ExitTick,
+}
+
+// Monitors several ports at once, showing their output as a single stream
+// with each line tagged by the port it came from. Keystrokes are sent to the
+// port which has the keyboard focus.
+pub async fn monitor_multi(
+    ports: Vec<(String, Transport)>,
+    encoder: &KeyEncoder,
+    opt: &Opt,
+) -> Result<()> {
+    let mut reader = EventStream::new();
+    let (event_writer, mut port_events) = mpsc::unbounded_channel();
+    let mut merger = LineMerger::new("\r\n");
+
+    let mut ports: Vec<MultiPort> = ports
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, port))| {
+            let color = PORT_COLORS[index % PORT_COLORS.len()];
+            MultiPort {
+                prefix: format!("{}", format!("[{}] ", short_name(&name)).with(color)),
+                name,
+                writer: spawn_port(index, port, event_writer.clone()),
+                decoder: StringDecoder::new(),
+                data: BytesMut::new(),
+                open: true,
+            }
+        })
+        .collect();
+    drop(event_writer);
+
+    let mut exit = ExitKey::new(exit_key(opt), opt.exit_double);
+    let menu_code = menu_code();
+    let mut menu_pending = false;
+    let mut focus = 0;
+
+    loop {
+        let multi_event = {
+            let mut event = reader.next().fuse();
+            let port_event = port_events.recv().fuse();
+            let exit_event = exit.wait().fuse();
+            pin_mut!(port_event, exit_event);
+
+            select! {
+                maybe_event = event => MultiEvent::Terminal(maybe_event),
+                maybe_port = port_event => MultiEvent::Port(maybe_port),
+                _ = exit_event => MultiEvent::ExitTick,
+            }
+        };
+
+        match multi_event {
+            MultiEvent::Terminal(maybe_event) => match maybe_event {
+                Some(Ok(event)) => {
+                    if let Event::Key(key_event) = event {
+                        // Some terminals (i.e. on Windows) report keys being
+                        // released as well as pressed.
+                        if key_event.kind == KeyEventKind::Release {
+                            continue;
+                        }
+                        match exit.check(&key_event) {
+                            ExitCheck::Exit => break,
+                            ExitCheck::Wait => continue,
+                            ExitCheck::Pass(Some(held)) => {
+                                if let Some(key) = handle_key_event(held, encoder, opt)? {
+                                    if ports[focus].open {
+                                        let _ = ports[focus].writer.send(key);
+                                    }
+                                }
+                            }
+                            ExitCheck::Pass(None) => {}
+                        }
+                        if menu_pending {
+                            menu_pending = false;
+                            focus = match menu_action(&key_event) {
+                                Some(MenuAction::SendMenuKey) => {
+                                    if let Some(byte) = control_char(MENU_CHAR) {
+                                        if ports[focus].open {
+                                            let _ =
+                                                ports[focus].writer.send(Bytes::from(vec![byte]));
+                                        }
+                                    }
+                                    continue;
+                                }
+                                Some(MenuAction::NextPort) => (focus + 1) % ports.len(),
+                                Some(MenuAction::SelectPort(index)) if index < ports.len() => index,
+                                _ => {
+                                    print!("\r\n{}", menu_help());
+                                    std::io::stdout().flush()?;
+                                    continue;
+                                }
+                            };
+                            println!(
+                                "\r\n{}Keyboard focus is now on {}\r",
+                                ports[focus].prefix, ports[focus].name
+                            );
+                            merger = LineMerger::new("\r\n");
+                            continue;
+                        } else if event == menu_code {
+                            menu_pending = true;
+                            continue;
+                        }
+                        if let Some(key) = handle_key_event(key_event, encoder, opt)? {
+                            if ports[focus].open {
+                                let _ = ports[focus].writer.send(key);
+                            }
+                        }
+                    } else if opt.debug {
+                        println!("Unrecognized Event::{:?}\r", event);
+                    }
+                }
+                Some(Err(e)) => println!("crossterm Error: {:?}\r", e),
+                None => {
+                    println!("maybe_event returned None\r");
+                }
+            },
+            MultiEvent::Port(Some(PortEvent::Data(index, data))) => {
+                let port = &mut ports[index];
+                port.data.extend_from_slice(&data);
+                let mut text = String::new();
+                while !port.data.is_empty() {
+                    if let Some(decoded) = port.decoder.decode(&mut port.data)? {
+                        text.push_str(&decoded);
+                    }
+                }
+                if opt.debug {
+                    println!("Serial Event:{}:{:?}\r", port.name, text);
+                } else {
+                    let prefix = port.prefix.clone();
+                    print!("{}", merger.merge(index, &text, &|| prefix.clone()));
+                    std::io::stdout().flush()?;
+                }
+            }
+            MultiEvent::Port(Some(PortEvent::Closed(index, err))) => {
+                let port = &mut ports[index];
+                port.open = false;
+                match err {
+                    Some(e) => println!("\r\n{}Serial Error: {:?}\r", port.prefix, e),
+                    None => println!("\r\n{}Port closed\r", port.prefix),
+                }
+                merger = LineMerger::new("\r\n");
+            }
+            MultiEvent::ExitTick => {
+                if let Some(held) = exit.take_held() {
+                    if let Some(key) = handle_key_event(held, encoder, opt)? {
+                        if ports[focus].open {
+                            let _ = ports[focus].writer.send(key);
+                        }
+                    }
+                }
+            }
+            // All of the ports have been closed.
+            MultiEvent::Port(None) => break,
+        }
+    }
+
+    Ok(())
+}
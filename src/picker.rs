@@ -0,0 +1,87 @@
+use crate::error::{ProgramError, Result};
+use crossterm::{
+    cursor,
+    event::{read, Event, KeyCode, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, disable_raw_mode, enable_raw_mode, ClearType},
+};
+use std::io::{stdout, Write};
+
+// Draws the list of choices, highlighting the selected one. The cursor is
+// left at the start of the line after the list.
+fn draw(choices: &[String], selected: usize) -> Result<()> {
+    // Some terminals report a width of 0, which means that it's unknown.
+    let width = match terminal::size() {
+        Ok((cols, _)) if cols > 0 => cols as usize,
+        _ => 80,
+    };
+    let mut out = stdout();
+    for (index, choice) in choices.iter().enumerate() {
+        // Lines are kept from wrapping, so that redrawing knows how many
+        // lines to move back up.
+        let line: String = format!("{} {}", index + 1, choice)
+            .chars()
+            .take(width.saturating_sub(3))
+            .collect();
+        queue!(out, terminal::Clear(ClearType::CurrentLine))?;
+        if index == selected {
+            queue!(
+                out,
+                Print("> "),
+                SetAttribute(Attribute::Reverse),
+                Print(line),
+                SetAttribute(Attribute::Reset)
+            )?;
+        } else {
+            queue!(out, Print("  "), Print(line))?;
+        }
+        queue!(out, Print("\r\n"))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Waits for keys until a choice is made, returning its index, or None if
+// the selection was cancelled.
+fn select(choices: &[String]) -> Result<Option<usize>> {
+    let mut selected = 0;
+    draw(choices, selected)?;
+    loop {
+        let key = match read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Ok(None);
+        }
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => selected = selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => selected = (selected + 1).min(choices.len() - 1),
+            KeyCode::Home => selected = 0,
+            KeyCode::End => selected = choices.len() - 1,
+            KeyCode::Char(ch @ '1'..='9') => {
+                let index = ch as usize - '1' as usize;
+                if index < choices.len() {
+                    return Ok(Some(index));
+                }
+            }
+            KeyCode::Enter => return Ok(Some(selected)),
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+            _ => {}
+        }
+        queue!(stdout(), cursor::MoveUp(choices.len() as u16))?;
+        draw(choices, selected)?;
+    }
+}
+
+// Shows a list of choices and lets the user pick one using the arrow keys
+// (or its number), returning the index of the one picked.
+pub fn pick(title: &str, choices: &[String]) -> Result<usize> {
+    println!("{}", title);
+    println!("Use Up/Down and Enter to select, or Esc to cancel");
+    enable_raw_mode()?;
+    let result = select(choices);
+    disable_raw_mode()?;
+    result?.ok_or(ProgramError::NoPortSelected)
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Terminals report lots of sizes while a window is being dragged, so the size
+// is only sent once it has stopped changing for this long.
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// How the device is told about the size of the terminal.
+#[derive(Clone, Copy, Debug, PartialEq, strum::EnumString, strum::VariantNames)]
+#[strum(serialize_all = "snake_case")]
+pub enum ResizeForward {
+    /// Type a stty rows N cols M command (for a Linux shell).
+    Stty,
+    /// Send the xterm window size report (ESC [8;rows;cols t).
+    Xterm,
+}
+
+/// Keeps track of changes to the size of the terminal, and works out what
+/// to send to the device once the size settles.
+pub struct ResizeForwarder {
+    how: Option<ResizeForward>,
+    /// The latest size (columns, rows) which hasn't been sent yet.
+    pending: Option<(u16, u16)>,
+    /// When the pending size is sent, unless it changes again.
+    send_at: Option<Instant>,
+}
+
+impl ResizeForwarder {
+    pub fn new(how: Option<ResizeForward>) -> ResizeForwarder {
+        ResizeForwarder {
+            how,
+            pending: None,
+            send_at: None,
+        }
+    }
+
+    // Notes that the terminal has been resized. Nothing happens unless
+    // forwarding was asked for.
+    pub fn resized(&mut self, cols: u16, rows: u16) {
+        if self.how.is_some() {
+            self.pending = Some((cols, rows));
+            self.send_at = Some(Instant::now() + SETTLE_TIME);
+        }
+    }
+
+    // Waits until the pending size should be sent, or forever if there isn't one.
+    pub async fn wait(&self) {
+        match self.send_at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    // Returns the data which tells the device about the pending size. enter
+    // is what ends the stty command.
+    pub fn take(&mut self, enter: &[u8]) -> Option<Vec<u8>> {
+        self.send_at = None;
+        let (cols, rows) = self.pending.take()?;
+        match self.how? {
+            ResizeForward::Stty => {
+                let mut data = format!("stty rows {} cols {}", rows, cols).into_bytes();
+                data.extend_from_slice(enter);
+                Some(data)
+            }
+            ResizeForward::Xterm => Some(format!("\x1b[8;{};{}t", rows, cols).into_bytes()),
+        }
+    }
+}
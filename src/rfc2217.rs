@@ -0,0 +1,345 @@
+// Support for the Telnet Com Port Control Option described in RFC 2217.
+
+use crate::telnet::{subnegotiation, OPT_COM_PORT};
+use crate::transport::{LineCommand, LineSettings};
+use std::convert::TryInto;
+use tokio_serial::{ClearBuffer, DataBits, FlowControl, Parity, StopBits};
+
+// Commands sent from the client to the server. The server replies using
+// the same command plus SERVER_OFFSET.
+const SIGNATURE: u8 = 0;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const SET_LINESTATE_MASK: u8 = 10;
+const SET_MODEMSTATE_MASK: u8 = 11;
+const PURGE_DATA: u8 = 12;
+const SERVER_OFFSET: u8 = 100;
+
+// Values used with SET_CONTROL.
+const CONTROL_FLOW_REQUEST: u8 = 0;
+const CONTROL_FLOW_NONE: u8 = 1;
+const CONTROL_FLOW_SOFTWARE: u8 = 2;
+const CONTROL_FLOW_HARDWARE: u8 = 3;
+const CONTROL_BREAK_REQUEST: u8 = 4;
+const CONTROL_BREAK_ON: u8 = 5;
+const CONTROL_BREAK_OFF: u8 = 6;
+const CONTROL_DTR_REQUEST: u8 = 7;
+const CONTROL_DTR_ON: u8 = 8;
+const CONTROL_DTR_OFF: u8 = 9;
+const CONTROL_RTS_REQUEST: u8 = 10;
+const CONTROL_RTS_ON: u8 = 11;
+const CONTROL_RTS_OFF: u8 = 12;
+
+// Values used with PURGE_DATA.
+const PURGE_RX: u8 = 1;
+const PURGE_TX: u8 = 2;
+const PURGE_BOTH: u8 = 3;
+
+fn data_bits_code(data_bits: DataBits) -> u8 {
+    match data_bits {
+        DataBits::Five => 5,
+        DataBits::Six => 6,
+        DataBits::Seven => 7,
+        DataBits::Eight => 8,
+    }
+}
+
+fn data_bits_from_code(code: u8) -> Option<DataBits> {
+    match code {
+        5 => Some(DataBits::Five),
+        6 => Some(DataBits::Six),
+        7 => Some(DataBits::Seven),
+        8 => Some(DataBits::Eight),
+        _ => None,
+    }
+}
+
+fn parity_code(parity: Parity) -> u8 {
+    match parity {
+        Parity::None => 1,
+        Parity::Odd => 2,
+        Parity::Even => 3,
+    }
+}
+
+// Mark (4) and space (5) parity aren't supported by the serialport crate.
+fn parity_from_code(code: u8) -> Option<Parity> {
+    match code {
+        1 => Some(Parity::None),
+        2 => Some(Parity::Odd),
+        3 => Some(Parity::Even),
+        _ => None,
+    }
+}
+
+fn stop_bits_code(stop_bits: StopBits) -> u8 {
+    match stop_bits {
+        StopBits::One => 1,
+        StopBits::Two => 2,
+    }
+}
+
+// 1.5 stop bits (3) isn't supported by the serialport crate.
+fn stop_bits_from_code(code: u8) -> Option<StopBits> {
+    match code {
+        1 => Some(StopBits::One),
+        2 => Some(StopBits::Two),
+        _ => None,
+    }
+}
+
+fn flow_control_code(flow_control: FlowControl) -> u8 {
+    match flow_control {
+        FlowControl::None => CONTROL_FLOW_NONE,
+        FlowControl::Software => CONTROL_FLOW_SOFTWARE,
+        FlowControl::Hardware => CONTROL_FLOW_HARDWARE,
+    }
+}
+
+fn on_off_code(on: bool, on_code: u8, off_code: u8) -> u8 {
+    if on {
+        on_code
+    } else {
+        off_code
+    }
+}
+
+// Encodes a LineCommand as the subnegotiation which a client sends to the server.
+pub fn client_request(cmd: &LineCommand) -> Vec<u8> {
+    let payload = match *cmd {
+        LineCommand::Baud(baud) => {
+            let mut payload = vec![SET_BAUDRATE];
+            payload.extend_from_slice(&baud.to_be_bytes());
+            payload
+        }
+        LineCommand::DataBits(data_bits) => vec![SET_DATASIZE, data_bits_code(data_bits)],
+        LineCommand::Parity(parity) => vec![SET_PARITY, parity_code(parity)],
+        LineCommand::StopBits(stop_bits) => vec![SET_STOPSIZE, stop_bits_code(stop_bits)],
+        LineCommand::FlowControl(flow_control) => {
+            vec![SET_CONTROL, flow_control_code(flow_control)]
+        }
+        LineCommand::Break(on) => vec![
+            SET_CONTROL,
+            on_off_code(on, CONTROL_BREAK_ON, CONTROL_BREAK_OFF),
+        ],
+        LineCommand::Dtr(on) => vec![
+            SET_CONTROL,
+            on_off_code(on, CONTROL_DTR_ON, CONTROL_DTR_OFF),
+        ],
+        LineCommand::Rts(on) => vec![
+            SET_CONTROL,
+            on_off_code(on, CONTROL_RTS_ON, CONTROL_RTS_OFF),
+        ],
+        LineCommand::Purge(buffer) => vec![
+            PURGE_DATA,
+            match buffer {
+                ClearBuffer::Input => PURGE_RX,
+                ClearBuffer::Output => PURGE_TX,
+                ClearBuffer::All => PURGE_BOTH,
+            },
+        ],
+    };
+    subnegotiation(OPT_COM_PORT, &payload)
+}
+
+// Decodes a com port subnegotiation received by the server, returning the
+// command which should be applied to the port (if any). Queries, and
+// requests for settings which can't be supported, don't change anything.
+pub fn server_request(payload: &[u8]) -> Option<LineCommand> {
+    let (&command, value) = payload.split_first()?;
+    let arg = value.first().copied().unwrap_or(0);
+
+    match command {
+        SET_BAUDRATE => {
+            let baud = u32::from_be_bytes(value.get(..4)?.try_into().ok()?);
+            (baud != 0).then_some(LineCommand::Baud(baud))
+        }
+        SET_DATASIZE => data_bits_from_code(arg).map(LineCommand::DataBits),
+        SET_PARITY => parity_from_code(arg).map(LineCommand::Parity),
+        SET_STOPSIZE => stop_bits_from_code(arg).map(LineCommand::StopBits),
+        SET_CONTROL => match arg {
+            CONTROL_FLOW_NONE => Some(LineCommand::FlowControl(FlowControl::None)),
+            CONTROL_FLOW_SOFTWARE => Some(LineCommand::FlowControl(FlowControl::Software)),
+            CONTROL_FLOW_HARDWARE => Some(LineCommand::FlowControl(FlowControl::Hardware)),
+            CONTROL_BREAK_ON => Some(LineCommand::Break(true)),
+            CONTROL_BREAK_OFF => Some(LineCommand::Break(false)),
+            CONTROL_DTR_ON => Some(LineCommand::Dtr(true)),
+            CONTROL_DTR_OFF => Some(LineCommand::Dtr(false)),
+            CONTROL_RTS_ON => Some(LineCommand::Rts(true)),
+            CONTROL_RTS_OFF => Some(LineCommand::Rts(false)),
+            _ => None,
+        },
+        PURGE_DATA => match arg {
+            PURGE_RX => Some(LineCommand::Purge(ClearBuffer::Input)),
+            PURGE_TX => Some(LineCommand::Purge(ClearBuffer::Output)),
+            PURGE_BOTH => Some(LineCommand::Purge(ClearBuffer::All)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Returns the reply which the server sends to a com port subnegotiation (if
+// any). Settings are answered with the value in effect once the request has
+// been dealt with, so a request which couldn't be applied gets the current
+// setting back.
+pub fn server_reply(payload: &[u8], settings: &LineSettings) -> Option<Vec<u8>> {
+    let (&command, value) = payload.split_first()?;
+    let arg = value.first().copied().unwrap_or(0);
+
+    let reply_value = match command {
+        SIGNATURE => {
+            if !value.is_empty() {
+                // This is the client telling us its signature.
+                return None;
+            }
+            let signature = format!("serial-monitor {}", env!("CARGO_PKG_VERSION"));
+            signature.into_bytes()
+        }
+        SET_BAUDRATE if value.len() < 4 => return None,
+        SET_BAUDRATE => settings.baud.to_be_bytes().to_vec(),
+        SET_DATASIZE => vec![data_bits_code(settings.data_bits)],
+        SET_PARITY => vec![parity_code(settings.parity)],
+        SET_STOPSIZE => vec![stop_bits_code(settings.stop_bits)],
+        SET_CONTROL => match arg {
+            CONTROL_FLOW_REQUEST..=CONTROL_FLOW_HARDWARE => {
+                vec![flow_control_code(settings.flow_control)]
+            }
+            CONTROL_BREAK_REQUEST..=CONTROL_BREAK_OFF => vec![on_off_code(
+                settings.break_on,
+                CONTROL_BREAK_ON,
+                CONTROL_BREAK_OFF,
+            )],
+            CONTROL_DTR_REQUEST..=CONTROL_DTR_OFF => vec![on_off_code(
+                settings.dtr.unwrap_or(true),
+                CONTROL_DTR_ON,
+                CONTROL_DTR_OFF,
+            )],
+            CONTROL_RTS_REQUEST..=CONTROL_RTS_OFF => vec![on_off_code(
+                settings.rts.unwrap_or(true),
+                CONTROL_RTS_ON,
+                CONTROL_RTS_OFF,
+            )],
+            // Inbound flow control settings aren't supported.
+            _ => vec![arg],
+        },
+        // Line and modem state notifications aren't sent, so the masks are
+        // simply acknowledged.
+        SET_LINESTATE_MASK | SET_MODEMSTATE_MASK | PURGE_DATA => vec![arg],
+        _ => return None,
+    };
+
+    let mut reply = vec![command + SERVER_OFFSET];
+    reply.extend_from_slice(&reply_value);
+    Some(subnegotiation(OPT_COM_PORT, &reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telnet::{TelnetEvent, TelnetParser};
+
+    fn settings() -> LineSettings {
+        LineSettings {
+            baud: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None,
+            break_on: false,
+        }
+    }
+
+    // Decodes a subnegotiation as the server would receive it.
+    fn payload(command: &[u8]) -> Vec<u8> {
+        let mut data = command.to_vec();
+        let (len, mut events) = TelnetParser::new().decode(&mut data);
+        assert_eq!(len, 0);
+        match events.pop() {
+            Some(TelnetEvent::Subnegotiation(OPT_COM_PORT, payload)) => payload,
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn client_request_round_trips() {
+        let commands = [
+            // 0xff in the baud rate has to be escaped.
+            LineCommand::Baud(0x0001_c2ff),
+            LineCommand::DataBits(DataBits::Seven),
+            LineCommand::Parity(Parity::Even),
+            LineCommand::StopBits(StopBits::Two),
+            LineCommand::FlowControl(FlowControl::Hardware),
+            LineCommand::Break(true),
+            LineCommand::Dtr(false),
+            LineCommand::Rts(true),
+            LineCommand::Purge(ClearBuffer::All),
+        ];
+        for cmd in commands.iter() {
+            assert_eq!(server_request(&payload(&client_request(cmd))), Some(*cmd));
+        }
+    }
+
+    #[test]
+    fn server_reply_shows_applied_settings() {
+        let mut settings = settings();
+        let request = payload(&client_request(&LineCommand::Baud(9600)));
+        settings.apply(&server_request(&request).unwrap());
+        assert_eq!(
+            server_reply(&request, &settings),
+            Some(subnegotiation(
+                OPT_COM_PORT,
+                &[SET_BAUDRATE + SERVER_OFFSET, 0, 0, 0x25, 0x80]
+            ))
+        );
+    }
+
+    #[test]
+    fn server_reply_shows_settings_not_applied() {
+        let settings = settings();
+        // Mark parity isn't supported, so the current parity is sent back.
+        assert!(server_request(&[SET_PARITY, 4]).is_none());
+        assert_eq!(
+            server_reply(&[SET_PARITY, 4], &settings),
+            Some(subnegotiation(
+                OPT_COM_PORT,
+                &[SET_PARITY + SERVER_OFFSET, 1]
+            ))
+        );
+    }
+
+    #[test]
+    fn server_reply_answers_queries() {
+        let mut settings = settings();
+        assert!(server_request(&[SET_CONTROL, CONTROL_BREAK_REQUEST]).is_none());
+        settings.apply(&LineCommand::Break(true));
+        settings.apply(&LineCommand::Dtr(false));
+        let queries = [
+            (CONTROL_FLOW_REQUEST, CONTROL_FLOW_NONE),
+            (CONTROL_BREAK_REQUEST, CONTROL_BREAK_ON),
+            (CONTROL_DTR_REQUEST, CONTROL_DTR_OFF),
+            (CONTROL_RTS_REQUEST, CONTROL_RTS_ON),
+        ];
+        for (query, answer) in queries.iter() {
+            assert_eq!(
+                server_reply(&[SET_CONTROL, *query], &settings),
+                Some(subnegotiation(
+                    OPT_COM_PORT,
+                    &[SET_CONTROL + SERVER_OFFSET, *answer]
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn server_ignores_client_signature() {
+        assert!(server_request(b"\0client").is_none());
+        assert!(server_reply(b"\0client", &settings()).is_none());
+        assert!(server_reply(&[SIGNATURE], &settings()).is_some());
+    }
+}
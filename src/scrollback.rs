@@ -0,0 +1,415 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::io::Write;
+
+// Switches to and from the terminal's alternate screen, so that the screen
+// is put back the way it was when the view resumes.
+const ENTER_VIEW: &str = "\x1b[?1049h";
+const LEAVE_VIEW: &str = "\x1b[?1049l";
+
+// The most output which is held back while the view is frozen. Only the end
+// of it is on the screen once the view resumes, so older output is dropped.
+const MAX_HELD: usize = 64 * 1024;
+
+// The longest unfinished line which is kept (i.e. from a progress bar which
+// only uses carriage returns, or binary data without any newlines).
+const MAX_PARTIAL: usize = 8 * 1024;
+
+// Returns a line of output the way it appears on the screen, without escape
+// sequences or other control characters. Only the text after the last
+// carriage return is kept, since that's what overwrote the rest.
+fn clean_line(raw: &str) -> String {
+    let raw = raw.rsplit('\r').find(|s| !s.is_empty()).unwrap_or("");
+    let mut line = String::new();
+    let mut chars = raw.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\x1b' => match chars.next() {
+                // CSI sequences end with a character from @ to ~.
+                Some('[') => {
+                    for ch in chars.by_ref() {
+                        if ('@'..='~').contains(&ch) {
+                            break;
+                        }
+                    }
+                }
+                // OSC sequences end with BEL or ESC \.
+                Some(']') => loop {
+                    match chars.next() {
+                        Some('\x07') | None => break,
+                        Some('\x1b') => {
+                            chars.next();
+                            break;
+                        }
+                        Some(_) => {}
+                    }
+                },
+                _ => {}
+            },
+            '\t' => {
+                let spaces = 8 - line.chars().count() % 8;
+                line.extend(std::iter::repeat_n(' ', spaces));
+            }
+            ch if ch.is_control() => {}
+            ch => line.push(ch),
+        }
+    }
+    line
+}
+
+// Returns the size of the terminal (some terminals report 0 when it isn't
+// known).
+pub fn screen_size() -> (usize, usize) {
+    match crossterm::terminal::size() {
+        Ok((cols, rows)) if cols > 0 && rows > 1 => (cols as usize, rows as usize),
+        _ => (80, 24),
+    }
+}
+
+// State of the frozen view.
+struct View {
+    /// Index of the line at the top of the screen.
+    top: usize,
+    /// The number of lines received since the view was frozen.
+    new_lines: usize,
+    /// The pattern being typed after pressing /.
+    typing: Option<String>,
+    pattern: Option<Regex>,
+    /// Index of the line which the last search found.
+    found: Option<usize>,
+    /// Shown in the status line (i.e. when a search doesn't find anything).
+    message: String,
+    /// Output received while frozen, which is shown once the view resumes.
+    held: String,
+}
+
+impl View {
+    // Adds to the output which is shown once the view resumes.
+    fn hold(&mut self, text: &str) {
+        self.held.push_str(text);
+        if self.held.len() > MAX_HELD {
+            // Drop whole lines, so that the rest starts at a line.
+            let mut start = self.held.len() - MAX_HELD;
+            while !self.held.is_char_boundary(start) {
+                start += 1;
+            }
+            if let Some(idx) = self.held[start..].find('\n') {
+                start += idx + 1;
+            }
+            self.held.drain(..start);
+        }
+    }
+}
+
+/// Keeps the most recent lines of output, so that they can be looked
+/// through after they've scrolled off the screen. While the view is frozen,
+/// output keeps being added to the buffer but isn't shown.
+pub struct Scrollback {
+    lines: VecDeque<String>,
+    max_lines: usize,
+    /// Output received since the last newline.
+    partial: String,
+    view: Option<View>,
+    /// Output which was held back while the view was frozen, until it's
+    /// taken by take_held.
+    held: String,
+}
+
+impl Scrollback {
+    pub fn new(max_lines: usize) -> Scrollback {
+        Scrollback {
+            lines: VecDeque::new(),
+            max_lines,
+            partial: String::new(),
+            view: None,
+            held: String::new(),
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.view.is_some()
+    }
+
+    // Adds output received from the port.
+    pub fn push(&mut self, text: &str) {
+        if let Some(view) = &mut self.view {
+            view.hold(text);
+        }
+        if self.max_lines == 0 {
+            return;
+        }
+        // Only the new text is searched for newlines.
+        for piece in text.split_inclusive('\n') {
+            self.partial.push_str(piece);
+            if !piece.ends_with('\n') {
+                self.trim_partial();
+                break;
+            }
+            let raw = std::mem::take(&mut self.partial);
+            self.lines.push_back(clean_line(&raw[..raw.len() - 1]));
+            let dropped = self.lines.len() > self.max_lines;
+            if dropped {
+                self.lines.pop_front();
+            }
+            if let Some(view) = &mut self.view {
+                view.new_lines += 1;
+                if dropped {
+                    // Keep the same lines on the screen.
+                    view.top = view.top.saturating_sub(1);
+                    view.found = view.found.and_then(|found| found.checked_sub(1));
+                }
+            }
+        }
+    }
+
+    // Keeps the unfinished line from growing without bound. Only the text
+    // after the last carriage return shows (see clean_line), so the rest is
+    // dropped, and whatever is left is cut down to MAX_PARTIAL.
+    fn trim_partial(&mut self) {
+        if self.partial.len() <= MAX_PARTIAL {
+            return;
+        }
+        let shown = self.partial.trim_end_matches('\r');
+        let mut start = shown.rfind('\r').map_or(0, |idx| idx + 1);
+        if self.partial.len() - start > MAX_PARTIAL {
+            start = self.partial.len() - MAX_PARTIAL;
+            while !self.partial.is_char_boundary(start) {
+                start += 1;
+            }
+        }
+        self.partial.drain(..start);
+    }
+
+    // Holds back a message (i.e. about a TCP client) while the view is
+    // frozen, to be shown on a line of its own once it resumes. Returns false
+    // if the view isn't frozen.
+    pub fn hold_message(&mut self, message: &str) -> bool {
+        match &mut self.view {
+            Some(view) => {
+                let start = if view.held.is_empty() || view.held.ends_with('\n') {
+                    ""
+                } else {
+                    "\r\n"
+                };
+                view.hold(&format!("{}{}\r\n", start, message));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The number of lines which can be viewed, including the partial line.
+    fn line_count(&self) -> usize {
+        self.lines.len() + usize::from(!self.partial.is_empty())
+    }
+
+    fn line(&self, index: usize) -> String {
+        match self.lines.get(index) {
+            Some(line) => line.clone(),
+            None => clean_line(&self.partial),
+        }
+    }
+
+    // Returns the index of the top line when showing the last page.
+    fn last_top(&self) -> usize {
+        self.line_count().saturating_sub(screen_size().1 - 1)
+    }
+
+    // Freezes the view at the most recent output, returning what needs to
+    // be printed to show it.
+    pub fn freeze(&mut self) -> String {
+        self.view = Some(View {
+            top: self.last_top(),
+            new_lines: 0,
+            typing: None,
+            pattern: None,
+            found: None,
+            message: String::new(),
+            held: String::new(),
+        });
+        format!("{}{}", ENTER_VIEW, self.draw())
+    }
+
+    // Goes back to following the output, returning what needs to be printed
+    // to restore the screen. The output which was held back is returned by
+    // take_held.
+    pub fn resume(&mut self) -> String {
+        match self.view.take() {
+            Some(view) => {
+                self.held = view.held;
+                LEAVE_VIEW.to_string()
+            }
+            None => String::new(),
+        }
+    }
+
+    // Returns the output which was received while the view was frozen, once
+    // it has resumed.
+    pub fn take_held(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+
+    // Returns what needs to be printed to draw the frozen view.
+    pub fn draw(&self) -> String {
+        let view = match &self.view {
+            Some(view) => view,
+            None => return String::new(),
+        };
+        let (cols, rows) = screen_size();
+        let height = rows - 1;
+        let mut output = String::new();
+        for row in 0..height {
+            output.push_str(&format!("\x1b[{};1H\x1b[K", row + 1));
+            let index = view.top + row;
+            if index >= self.line_count() {
+                continue;
+            }
+            let line: String = self.line(index).chars().take(cols).collect();
+            match &view.pattern {
+                Some(pattern) => {
+                    let mut end = 0;
+                    for found in pattern.find_iter(&line) {
+                        output.push_str(&line[end..found.start()]);
+                        output.push_str(&format!("\x1b[7m{}\x1b[27m", found.as_str()));
+                        end = found.end();
+                    }
+                    output.push_str(&line[end..]);
+                }
+                None => output.push_str(&line),
+            }
+        }
+        let status = match &view.typing {
+            Some(typed) => format!("/{}", typed),
+            None => {
+                let mut status = format!(
+                    "Lines {}-{} of {}",
+                    (view.top + 1).min(self.line_count()),
+                    (view.top + height).min(self.line_count()),
+                    self.line_count()
+                );
+                if view.new_lines > 0 {
+                    status.push_str(&format!(", {} new", view.new_lines));
+                }
+                if !view.message.is_empty() {
+                    status.push_str(&format!(" - {}", view.message));
+                }
+                status.push_str(
+                    " - PageUp/PageDown scroll, / search, n/N next/previous match, q resume",
+                );
+                status
+            }
+        };
+        let status: String = status.chars().take(cols).collect();
+        output.push_str(&format!("\x1b[{};1H\x1b[K\x1b[7m{}\x1b[0m", rows, status));
+        output
+    }
+
+    // Moves the view up (negative) or down by a number of lines.
+    fn scroll(&mut self, lines: isize) {
+        let last_top = self.last_top();
+        if let Some(view) = &mut self.view {
+            view.top = view.top.saturating_add_signed(lines).min(last_top);
+        }
+    }
+
+    // Looks for the next line (older or newer) which matches the pattern,
+    // and scrolls so that it's at the top of the screen.
+    fn search(&mut self, older: bool) {
+        let count = self.line_count();
+        let height = screen_size().1 - 1;
+        let last_top = self.last_top();
+        let view = match &self.view {
+            Some(view) => view,
+            None => return,
+        };
+        let pattern = match &view.pattern {
+            Some(pattern) => pattern,
+            None => return,
+        };
+        // The first search starts from the bottom of the screen.
+        let found = if older {
+            let before = view.found.unwrap_or((view.top + height).min(count));
+            (0..before)
+                .rev()
+                .find(|&index| pattern.is_match(&self.line(index)))
+        } else {
+            let after = view.found.map_or(view.top, |found| found + 1);
+            (after..count).find(|&index| pattern.is_match(&self.line(index)))
+        };
+        if let Some(view) = &mut self.view {
+            match found {
+                Some(index) => {
+                    view.found = Some(index);
+                    view.top = index.min(last_top);
+                    view.message.clear();
+                }
+                None => view.message = "Pattern not found".to_string(),
+            }
+        }
+    }
+
+    // Handles a key pressed while the view is frozen, returning what needs to
+    // be printed.
+    pub fn handle_key(&mut self, key_event: &KeyEvent) -> String {
+        let height = (screen_size().1 - 1) as isize;
+        let view = match &mut self.view {
+            Some(view) => view,
+            None => return String::new(),
+        };
+        if let Some(typed) = &mut view.typing {
+            match key_event.code {
+                KeyCode::Char(ch) => typed.push(ch),
+                KeyCode::Backspace if !typed.is_empty() => {
+                    typed.pop();
+                }
+                KeyCode::Enter => {
+                    match Regex::new(typed) {
+                        Ok(pattern) => {
+                            view.pattern = Some(pattern);
+                            view.found = None;
+                        }
+                        Err(e) => {
+                            // The last line of the message says what's wrong.
+                            let e = e.to_string();
+                            let reason = e.lines().last().unwrap_or_default();
+                            let reason = reason.trim_start_matches("error: ");
+                            view.message = format!("Invalid pattern: {}", reason);
+                            view.pattern = None;
+                        }
+                    }
+                    view.typing = None;
+                    self.search(true);
+                }
+                KeyCode::Backspace | KeyCode::Esc => view.typing = None,
+                _ => {}
+            }
+            return self.draw();
+        }
+        view.message.clear();
+        match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q') => return self.resume(),
+            KeyCode::PageUp | KeyCode::Char('b') => self.scroll(-height),
+            KeyCode::PageDown | KeyCode::Char(' ') => self.scroll(height),
+            KeyCode::Up | KeyCode::Char('k') => self.scroll(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.scroll(1),
+            KeyCode::Home | KeyCode::Char('g') => self.scroll(isize::MIN),
+            KeyCode::End | KeyCode::Char('G') => self.scroll(isize::MAX),
+            KeyCode::Char('/') => view.typing = Some(String::new()),
+            KeyCode::Char('n') => self.search(true),
+            KeyCode::Char('N') => self.search(false),
+            _ => {}
+        }
+        self.draw()
+    }
+}
+
+// Leaves the frozen view's screen however the session ends.
+impl Drop for Scrollback {
+    fn drop(&mut self) {
+        if self.is_frozen() {
+            print!("{}", self.resume());
+            let _ = std::io::stdout().flush();
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::error::{ProgramError, Result};
+use crate::multi::{spawn_port, LineMerger, PortEvent};
+use crate::string_decoder::StringDecoder;
+use crate::transport::{LineSettings, Transport};
+use crate::{hex_str, Opt};
+use futures::{future::FutureExt, pin_mut, select};
+use std::io::Write;
+use tokio::sync::mpsc;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+/// How the traffic seen by the sniffer is shown.
+#[derive(Clone, Copy, Debug, PartialEq, strum::EnumString, strum::VariantNames)]
+#[strum(serialize_all = "snake_case")]
+pub enum SniffFormat {
+    /// As text, with control characters shown as escapes (i.e. \r or \x03).
+    Text,
+    /// As hex bytes, followed by their ASCII rendering.
+    Hex,
+}
+
+// The direction markers, indexed by the port that the data was read from.
+// The first port is connected to the host, and the second to the device.
+const MARKERS: [&str; 2] = [">>", "<<"];
+
+// Returns the current local time, formatted for the start of a line.
+fn timestamp() -> String {
+    chrono::Local::now().format("%H:%M:%S%.3f").to_string()
+}
+
+// Returns text with control characters replaced by escapes. A newline is
+// kept after each \n so that line oriented traffic stays readable.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\\' => escaped.push_str("\\\\"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\x{:02x}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+enum SniffEvent {
+    Port(Option<PortEvent>),
+    Interrupted,
+}
+
+// Sits between two ports, forwarding everything received on one to the
+// other, and shows the traffic in both directions. This runs until either
+// port is closed, or Control-C is pressed.
+pub async fn sniff(
+    host_name: String,
+    device_name: &str,
+    settings: &LineSettings,
+    opt: &Opt,
+) -> Result<()> {
+    if opt.listen.is_some() || opt.control.is_some() {
+        return Err(ProgramError::IncompatibleOptions(
+            "--sniff can't be used with --listen or --control".to_string(),
+        ));
+    }
+    let (event_writer, mut port_events) = mpsc::unbounded_channel();
+    let host = Transport::open_serial(&host_name, settings)?;
+    let device = Transport::open_serial(device_name, settings)?;
+    let writers = [
+        spawn_port(0, host, event_writer.clone()),
+        spawn_port(1, device, event_writer),
+    ];
+    println!(
+        "Sniffing between {} (>> is host to device) and {} (<< is device to host)",
+        host_name, device_name
+    );
+    println!("Press Control-C to exit");
+
+    let mut decoders = [StringDecoder::new(), StringDecoder::new()];
+    let mut data = BytesMut::new();
+    let mut merger = LineMerger::new("\n");
+
+    // Control-C is only listened for once, so that a press between two port
+    // events isn't missed.
+    let ctrl_c = tokio::signal::ctrl_c().fuse();
+    pin_mut!(ctrl_c);
+
+    loop {
+        let sniff_event = {
+            let port_event = port_events.recv().fuse();
+            pin_mut!(port_event);
+
+            select! {
+                maybe_port = port_event => SniffEvent::Port(maybe_port),
+                _ = ctrl_c => SniffEvent::Interrupted,
+            }
+        };
+
+        match sniff_event {
+            SniffEvent::Port(Some(PortEvent::Data(index, bytes))) => {
+                // Forward the data before showing it, so that the sniffer
+                // adds as little delay as possible.
+                let _ = writers[1 - index].send(bytes.clone());
+                let output = match opt.sniff_format {
+                    SniffFormat::Hex => {
+                        format!("{} {} {}\n", timestamp(), MARKERS[index], hex_str(&bytes))
+                    }
+                    SniffFormat::Text => {
+                        data.extend_from_slice(&bytes);
+                        let mut text = String::new();
+                        while !data.is_empty() {
+                            if let Some(decoded) = decoders[index].decode(&mut data)? {
+                                text.push_str(&decoded);
+                            }
+                        }
+                        merger.merge(index, &escape_text(&text), &|| {
+                            format!("{} {} ", timestamp(), MARKERS[index])
+                        })
+                    }
+                };
+                print!("{}", output);
+                std::io::stdout().flush()?;
+            }
+            SniffEvent::Port(Some(PortEvent::Closed(index, err))) => {
+                let name = if index == 0 { &host_name } else { device_name };
+                print!("{}", merger.finish());
+                match err {
+                    Some(e) => println!("{}: Serial Error: {:?}", name, e),
+                    None => println!("{}: Port closed", name),
+                }
+                break;
+            }
+            SniffEvent::Port(None) | SniffEvent::Interrupted => break,
+        }
+    }
+
+    print!("{}", merger.finish());
+    Ok(())
+}
@@ -0,0 +1,367 @@
+use crate::monitor::Session;
+use crate::scrollback::screen_size;
+use std::io::Write;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// How often the status line is brought up to date.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Returns a byte count in a compact form (i.e. 1.5 KB).
+fn format_bytes(bytes: f64) -> String {
+    if bytes < 1024.0 {
+        format!("{} B", bytes.round())
+    } else if bytes < 1024.0 * 1024.0 {
+        format!("{:.1} KB", bytes / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes / (1024.0 * 1024.0))
+    }
+}
+
+// Where the output parser is in an escape sequence.
+#[derive(Clone, Copy, PartialEq)]
+enum ParseState {
+    Ground,
+    Escape,
+    /// An escape sequence with an intermediate character (i.e. ESC ( B).
+    EscapeIntermediate,
+    /// A CSI sequence (ESC [).
+    Csi,
+    /// An OSC sequence (ESC ]), which ends with BEL or ESC \.
+    Osc,
+    OscEscape,
+}
+
+/// Follows the escape sequences in the device's output, so that the status
+/// line is only drawn between them, and not while the device is using the
+/// saved cursor or the scrolling region itself (i.e. while vim or less is
+/// running).
+struct OutputParser {
+    state: ParseState,
+    /// The parameters of the current CSI sequence.
+    params: String,
+    /// The device has saved the cursor, and hasn't restored it yet.
+    saved_cursor: bool,
+    /// The device has set a scrolling region.
+    scroll_region: bool,
+    /// The device has switched to the alternate screen.
+    alt_screen: bool,
+}
+
+impl OutputParser {
+    fn new() -> OutputParser {
+        OutputParser {
+            state: ParseState::Ground,
+            params: String::new(),
+            saved_cursor: false,
+            scroll_region: false,
+            alt_screen: false,
+        }
+    }
+
+    fn feed(&mut self, text: &str) {
+        for ch in text.chars() {
+            self.state = match (self.state, ch) {
+                // CAN and SUB cancel any sequence.
+                (_, '\x18') | (_, '\x1a') => ParseState::Ground,
+                (ParseState::Osc, '\x07') => ParseState::Ground,
+                (ParseState::Osc, '\x1b') => ParseState::OscEscape,
+                (ParseState::Osc, _) => ParseState::Osc,
+                (ParseState::OscEscape, _) => ParseState::Ground,
+                (_, '\x1b') => ParseState::Escape,
+                (ParseState::Ground, _) => ParseState::Ground,
+                (ParseState::Escape, '[') => {
+                    self.params.clear();
+                    ParseState::Csi
+                }
+                (ParseState::Escape, ']') => ParseState::Osc,
+                (ParseState::Escape, '\x20'..='\x2f') => ParseState::EscapeIntermediate,
+                (ParseState::Escape, final_char) => {
+                    match final_char {
+                        // DECSC and DECRC
+                        '7' => self.saved_cursor = true,
+                        '8' => self.saved_cursor = false,
+                        // RIS
+                        'c' => {
+                            self.saved_cursor = false;
+                            self.scroll_region = false;
+                            self.alt_screen = false;
+                        }
+                        _ => {}
+                    }
+                    ParseState::Ground
+                }
+                (ParseState::EscapeIntermediate, _) => ParseState::Ground,
+                (ParseState::Csi, '\x20'..='\x3f') => {
+                    self.params.push(ch);
+                    ParseState::Csi
+                }
+                (ParseState::Csi, final_char) => {
+                    let params = self.params.as_str();
+                    match final_char {
+                        // DECSTBM (without margins, it resets the region).
+                        'r' => self.scroll_region = !params.is_empty(),
+                        // SCOSC and SCORC
+                        's' if params.is_empty() => self.saved_cursor = true,
+                        'u' if params.is_empty() => self.saved_cursor = false,
+                        'h' | 'l' if matches!(params, "?47" | "?1047" | "?1049") => {
+                            self.alt_screen = final_char == 'h';
+                        }
+                        _ => {}
+                    }
+                    ParseState::Ground
+                }
+            };
+        }
+    }
+
+    // Returns whether the device is using the saved cursor, the scrolling
+    // region or the alternate screen.
+    fn device_owned(&self) -> bool {
+        self.saved_cursor || self.scroll_region || self.alt_screen
+    }
+
+    // Returns whether the status line can be drawn without getting in the
+    // way of the device.
+    fn is_idle(&self) -> bool {
+        self.state == ParseState::Ground && !self.device_owned()
+    }
+}
+
+/// A line at the bottom of the screen showing the state of the session. The
+/// rest of the screen scrolls above it.
+pub struct StatusBar {
+    enabled: bool,
+    parser: OutputParser,
+    /// The scrolling region needs setting once the device is idle.
+    setup_pending: bool,
+    /// The status line needs drawing once the device is idle.
+    draw_pending: bool,
+    /// When the status line is next brought up to date.
+    next_update: Instant,
+    /// The received byte count at the last update, to work out the rate.
+    last_rx: u64,
+    last_update: Instant,
+    /// Bytes received per second, over the last update interval.
+    rx_rate: f64,
+    /// State of the CTS line, if it's known.
+    cts: Option<bool>,
+}
+
+impl StatusBar {
+    pub fn new(enabled: bool) -> StatusBar {
+        let now = Instant::now();
+        StatusBar {
+            enabled,
+            parser: OutputParser::new(),
+            setup_pending: false,
+            draw_pending: false,
+            next_update: now + UPDATE_INTERVAL,
+            last_rx: 0,
+            last_update: now,
+            rx_rate: 0.0,
+            cts: None,
+        }
+    }
+
+    // Returns what needs to be printed to keep the bottom line of the screen
+    // out of the scrolling region. This is needed again whenever the size
+    // of the screen changes. It's put off while the device is busy with
+    // the screen (see catch_up).
+    pub fn setup(&mut self) -> String {
+        if !self.enabled {
+            return String::new();
+        }
+        if !self.parser.is_idle() {
+            self.setup_pending = true;
+            return String::new();
+        }
+        self.setup_pending = false;
+        let (_, rows) = screen_size();
+        // Moving down and back up makes sure that the cursor isn't left on
+        // the bottom line (scrolling the screen if it was there). Setting the
+        // scrolling region moves the cursor, so it's saved and restored.
+        format!("\n\x1b[A\x1b7\x1b[1;{}r\x1b8", rows - 1)
+    }
+
+    // Returns the number of rows which the device gets to use, out of the
+    // rows on the screen.
+    pub fn device_rows(&self, rows: u16) -> u16 {
+        if self.enabled {
+            rows.saturating_sub(1)
+        } else {
+            rows
+        }
+    }
+
+    // Returns what needs to be printed to give the whole screen back.
+    fn teardown(&self) -> String {
+        if !self.enabled {
+            return String::new();
+        }
+        let (_, rows) = screen_size();
+        format!("\x1b7\x1b[r\x1b[{};1H\x1b[K\x1b8", rows)
+    }
+
+    // Waits until the status line should be brought up to date, or forever
+    // if it isn't being shown.
+    pub async fn wait(&self) {
+        if self.enabled {
+            tokio::time::sleep_until(self.next_update).await
+        } else {
+            futures::future::pending().await
+        }
+    }
+
+    // Works out the values which change over time.
+    pub fn update(&mut self, session: &mut Session) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        if elapsed > 0.0 {
+            self.rx_rate = session.rx_bytes.saturating_sub(self.last_rx) as f64 / elapsed;
+        }
+        self.last_rx = session.rx_bytes;
+        self.last_update = now;
+        self.next_update = now + UPDATE_INTERVAL;
+        self.cts = session.port.as_mut().and_then(|port| port.cts());
+    }
+
+    // Notes the output from the device which has been printed.
+    pub fn output(&mut self, text: &str) {
+        if self.enabled {
+            let owned = self.parser.device_owned();
+            self.parser.feed(text);
+            // The device may have left the scrolling region covering the
+            // whole screen once it's done with it.
+            if owned && !self.parser.device_owned() {
+                self.setup_pending = true;
+                self.draw_pending = true;
+            }
+        }
+    }
+
+    // Returns what needs to be printed to do the setup or drawing which was
+    // put off, if the device is now idle.
+    pub fn catch_up(&mut self, session: &Session, exit_label: &str) -> String {
+        if !self.parser.is_idle() {
+            return String::new();
+        }
+        let mut output = String::new();
+        if self.setup_pending {
+            output.push_str(&self.setup());
+        }
+        if self.draw_pending {
+            output.push_str(&self.draw(session, exit_label));
+        }
+        output
+    }
+
+    // Returns what needs to be printed to draw the status line. It's put off
+    // while the device is busy with the screen (see catch_up).
+    pub fn draw(&mut self, session: &Session, exit_label: &str) -> String {
+        if !self.enabled {
+            return String::new();
+        }
+        if !self.parser.is_idle() {
+            self.draw_pending = true;
+            return String::new();
+        }
+        self.draw_pending = false;
+        let line_state = |state: Option<bool>| match state {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "?",
+        };
+        let mut fields = vec![match session.port {
+            Some(_) => session.port_name.clone(),
+            None => format!("{} (released)", session.port_name),
+        }];
+        // Raw TCP and telnet connections don't have any line settings.
+        if !session.port_name.starts_with("tcp://") && !session.port_name.starts_with("telnet://") {
+            fields.push(session.settings.to_string());
+            fields.push(format!(
+                "DTR {} RTS {} CTS {}",
+                line_state(session.settings.dtr),
+                line_state(session.settings.rts),
+                line_state(self.cts)
+            ));
+        }
+        fields.push(format!(
+            "RX {} ({}/s) TX {}",
+            format_bytes(session.rx_bytes as f64),
+            format_bytes(self.rx_rate),
+            format_bytes(session.tx_bytes as f64)
+        ));
+        let uptime = session.connected_at.elapsed().as_secs();
+        fields.push(format!(
+            "{:02}:{:02}:{:02}",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60
+        ));
+        fields.push(match &session.log {
+            Some(log) => format!("log {}", log.path()),
+            None => "no log".to_string(),
+        });
+        fields.push(format!("{} to exit", exit_label));
+
+        let (cols, rows) = screen_size();
+        let text: String = format!(" {}", fields.join(" | "))
+            .chars()
+            .take(cols)
+            .collect();
+        format!(
+            "\x1b7\x1b[{};1H\x1b[K\x1b[7m{:width$}\x1b[0m\x1b8",
+            rows,
+            text,
+            width = cols
+        )
+    }
+}
+
+// The whole screen is given back however the session ends.
+impl Drop for StatusBar {
+    fn drop(&mut self) {
+        print!("{}", self.teardown());
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(chunks: &[&str]) -> OutputParser {
+        let mut parser = OutputParser::new();
+        for chunk in chunks {
+            parser.feed(chunk);
+        }
+        parser
+    }
+
+    #[test]
+    fn idle_between_sequences() {
+        assert!(parse(&["hello\r\n"]).is_idle());
+        assert!(parse(&["\x1b[32mgreen\x1b[0m"]).is_idle());
+        assert!(!parse(&["\x1b[3"]).is_idle());
+        assert!(parse(&["\x1b[3", "1m"]).is_idle());
+        assert!(!parse(&["\x1b]0;title"]).is_idle());
+        assert!(parse(&["\x1b]0;title\x07"]).is_idle());
+        assert!(parse(&["\x1b]0;title\x1b\\"]).is_idle());
+        assert!(parse(&["\x1b(B"]).is_idle());
+        assert!(parse(&["\x1b[3\x18"]).is_idle());
+    }
+
+    #[test]
+    fn device_owned_screen() {
+        assert!(!parse(&["\x1b7"]).is_idle());
+        assert!(parse(&["\x1b7", "text\x1b8"]).is_idle());
+        assert!(!parse(&["\x1b[s"]).is_idle());
+        assert!(parse(&["\x1b[s\x1b[u"]).is_idle());
+        assert!(!parse(&["\x1b[1;20r"]).is_idle());
+        assert!(parse(&["\x1b[1;20r", "\x1b[r"]).is_idle());
+        assert!(!parse(&["\x1b[?1049h"]).is_idle());
+        assert!(parse(&["\x1b[?1049h\x1b[1;5r\x1b7", "\x1bc"]).is_idle());
+        assert!(parse(&["\x1b[?1049h", "\x1b[?1049l"]).is_idle());
+    }
+}
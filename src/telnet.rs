@@ -0,0 +1,552 @@
+use crate::transport::{read_or_write, PortIo};
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// The most data which is queued up to send to the server. Once the queue is
+// this full, no more data is taken until some of it has been sent.
+const MAX_PENDING: usize = 4096;
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+
+pub const OPT_BINARY: u8 = 0;
+pub const OPT_ECHO: u8 = 1;
+pub const OPT_SGA: u8 = 3;
+pub const OPT_COM_PORT: u8 = 44;
+
+/// Telnet commands found in the data stream.
+#[derive(Debug, PartialEq)]
+pub enum TelnetEvent {
+    Will(u8),
+    Wont(u8),
+    Do(u8),
+    Dont(u8),
+    /// Subnegotiation for an option, with IAC escapes removed.
+    Subnegotiation(u8, Vec<u8>),
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    Iac,
+    Negotiate(u8),
+    Sb,
+    SbData,
+    SbIac,
+}
+
+/// What a byte from a telnet stream turned out to be.
+pub enum Decoded {
+    Data(u8),
+    Command(TelnetEvent),
+    /// Part of a command which isn't finished yet (or which is ignored).
+    Nothing,
+}
+
+/// Separates telnet commands from the data in a telnet stream.
+pub struct TelnetParser {
+    state: State,
+    sb_option: u8,
+    sb_data: Vec<u8>,
+}
+
+impl TelnetParser {
+    pub const fn new() -> TelnetParser {
+        TelnetParser {
+            state: State::Data,
+            sb_option: 0,
+            sb_data: Vec::new(),
+        }
+    }
+
+    // Removes the telnet commands from data (in place). Returns the number
+    // of data bytes remaining at the front of data, and the commands found.
+    pub fn decode(&mut self, data: &mut [u8]) -> (usize, Vec<TelnetEvent>) {
+        let mut events = Vec::new();
+        let mut len = 0;
+
+        for i in 0..data.len() {
+            match self.decode_byte(data[i]) {
+                Decoded::Data(byte) => {
+                    data[len] = byte;
+                    len += 1;
+                }
+                Decoded::Command(event) => events.push(event),
+                Decoded::Nothing => {}
+            }
+        }
+        (len, events)
+    }
+
+    // Works out what the next byte of the stream is, for when the data and
+    // commands need to be handled in the order they arrived.
+    pub fn decode_byte(&mut self, byte: u8) -> Decoded {
+        let mut decoded = Decoded::Nothing;
+        self.state = match self.state {
+            State::Data => {
+                if byte == IAC {
+                    State::Iac
+                } else {
+                    decoded = Decoded::Data(byte);
+                    State::Data
+                }
+            }
+            State::Iac => match byte {
+                IAC => {
+                    decoded = Decoded::Data(IAC);
+                    State::Data
+                }
+                WILL | WONT | DO | DONT => State::Negotiate(byte),
+                SB => State::Sb,
+                // Other commands (NOP, GA, etc) carry no information we need.
+                _ => State::Data,
+            },
+            State::Negotiate(command) => {
+                decoded = Decoded::Command(match command {
+                    WILL => TelnetEvent::Will(byte),
+                    WONT => TelnetEvent::Wont(byte),
+                    DO => TelnetEvent::Do(byte),
+                    _ => TelnetEvent::Dont(byte),
+                });
+                State::Data
+            }
+            State::Sb => {
+                self.sb_option = byte;
+                self.sb_data.clear();
+                State::SbData
+            }
+            State::SbData => {
+                if byte == IAC {
+                    State::SbIac
+                } else {
+                    self.sb_data.push(byte);
+                    State::SbData
+                }
+            }
+            State::SbIac => match byte {
+                SE => {
+                    decoded = Decoded::Command(TelnetEvent::Subnegotiation(
+                        self.sb_option,
+                        std::mem::take(&mut self.sb_data),
+                    ));
+                    State::Data
+                }
+                _ => {
+                    self.sb_data.push(byte);
+                    State::SbData
+                }
+            },
+        };
+        decoded
+    }
+}
+
+// Doubles any IAC bytes so that data can be sent over a telnet connection.
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data.iter() {
+        if *byte == IAC {
+            escaped.push(IAC);
+        }
+        escaped.push(*byte);
+    }
+    escaped
+}
+
+// Outside of binary mode, telnet requires that a CR is always followed by
+// either LF or NUL.
+fn nvt_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len());
+    for (i, byte) in data.iter().enumerate() {
+        encoded.push(*byte);
+        if *byte == b'\r' && data.get(i + 1) != Some(&b'\n') {
+            encoded.push(0);
+        }
+    }
+    encoded
+}
+
+// Builds a subnegotiation command for option containing payload.
+pub fn subnegotiation(option: u8, payload: &[u8]) -> Vec<u8> {
+    let mut command = vec![IAC, SB, option];
+    command.extend_from_slice(&escape(payload));
+    command.extend_from_slice(&[IAC, SE]);
+    command
+}
+
+/// Tracks which options have been enabled on each side of a telnet connection,
+/// and generates the replies needed to agree on them.
+pub struct TelnetOptions {
+    /// Options which we're willing to perform.
+    local_supported: Vec<u8>,
+    /// Options which we'd like the other end to perform.
+    remote_supported: Vec<u8>,
+    local_enabled: [bool; 256],
+    remote_enabled: [bool; 256],
+    /// Options we've offered (or asked for) which haven't been answered yet.
+    local_requested: Vec<u8>,
+    remote_requested: Vec<u8>,
+}
+
+impl TelnetOptions {
+    pub fn new(local_supported: &[u8], remote_supported: &[u8]) -> TelnetOptions {
+        TelnetOptions {
+            local_supported: local_supported.to_vec(),
+            remote_supported: remote_supported.to_vec(),
+            local_enabled: [false; 256],
+            remote_enabled: [false; 256],
+            local_requested: Vec::new(),
+            remote_requested: Vec::new(),
+        }
+    }
+
+    // Returns the commands which offer all of our supported options to the
+    // other end.
+    pub fn offer(&mut self) -> Vec<u8> {
+        let mut commands = Vec::new();
+        for option in self.local_supported.iter() {
+            commands.extend_from_slice(&[IAC, WILL, *option]);
+        }
+        for option in self.remote_supported.iter() {
+            commands.extend_from_slice(&[IAC, DO, *option]);
+        }
+        self.local_requested = self.local_supported.clone();
+        self.remote_requested = self.remote_supported.clone();
+        commands
+    }
+
+    // Returns true if we've agreed to perform option.
+    pub fn local_enabled(&self, option: u8) -> bool {
+        self.local_enabled[option as usize]
+    }
+
+    // Returns true if the other end has agreed to perform option.
+    pub fn remote_enabled(&self, option: u8) -> bool {
+        self.remote_enabled[option as usize]
+    }
+
+    // Updates the option state for a negotiation command, returning the
+    // reply (if any) which should be sent. Replies are only sent when the
+    // state changes and the command wasn't an answer to one of our own
+    // requests, which prevents negotiation loops.
+    pub fn negotiate(&mut self, event: &TelnetEvent) -> Option<[u8; 3]> {
+        match *event {
+            TelnetEvent::Do(option) => {
+                let requested = take_request(&mut self.local_requested, option);
+                if !self.local_supported.contains(&option) {
+                    Some([IAC, WONT, option])
+                } else if !self.local_enabled[option as usize] {
+                    self.local_enabled[option as usize] = true;
+                    reply_unless(requested, [IAC, WILL, option])
+                } else {
+                    None
+                }
+            }
+            TelnetEvent::Dont(option) => {
+                let requested = take_request(&mut self.local_requested, option);
+                if self.local_enabled[option as usize] {
+                    self.local_enabled[option as usize] = false;
+                    reply_unless(requested, [IAC, WONT, option])
+                } else {
+                    None
+                }
+            }
+            TelnetEvent::Will(option) => {
+                let requested = take_request(&mut self.remote_requested, option);
+                if !self.remote_supported.contains(&option) {
+                    Some([IAC, DONT, option])
+                } else if !self.remote_enabled[option as usize] {
+                    self.remote_enabled[option as usize] = true;
+                    reply_unless(requested, [IAC, DO, option])
+                } else {
+                    None
+                }
+            }
+            TelnetEvent::Wont(option) => {
+                let requested = take_request(&mut self.remote_requested, option);
+                if self.remote_enabled[option as usize] {
+                    self.remote_enabled[option as usize] = false;
+                    reply_unless(requested, [IAC, DONT, option])
+                } else {
+                    None
+                }
+            }
+            TelnetEvent::Subnegotiation(_, _) => None,
+        }
+    }
+}
+
+// Removes option from the list of outstanding requests, returning true if it was there.
+fn take_request(requested: &mut Vec<u8>, option: u8) -> bool {
+    let len = requested.len();
+    requested.retain(|requested_option| *requested_option != option);
+    requested.len() != len
+}
+
+fn reply_unless(requested: bool, reply: [u8; 3]) -> Option<[u8; 3]> {
+    if requested {
+        None
+    } else {
+        Some(reply)
+    }
+}
+
+/// The client end of a telnet connection.
+pub struct TelnetStream {
+    stream: TcpStream,
+    parser: TelnetParser,
+    options: TelnetOptions,
+    /// Commands which still need to be sent to the server.
+    pending: Vec<u8>,
+    /// Subnegotiations received from the server which haven't been collected.
+    subnegotiations: Vec<(u8, Vec<u8>)>,
+    /// True if the last data byte received was a CR.
+    last_was_cr: bool,
+}
+
+impl TelnetStream {
+    // Connects to a telnet server, offering the supplied options.
+    pub async fn connect(
+        addr: &str,
+        local_supported: &[u8],
+        remote_supported: &[u8],
+    ) -> io::Result<TelnetStream> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut options = TelnetOptions::new(local_supported, remote_supported);
+        let pending = options.offer();
+        Ok(TelnetStream {
+            stream,
+            parser: TelnetParser::new(),
+            options,
+            pending,
+            subnegotiations: Vec::new(),
+            last_was_cr: false,
+        })
+    }
+
+    // Queues a command to be sent to the server with the next read or write.
+    pub fn queue_command(&mut self, command: &[u8]) {
+        self.pending.extend_from_slice(command);
+    }
+
+    // Returns any subnegotiations which have been received from the server.
+    pub fn take_subnegotiations(&mut self) -> Vec<(u8, Vec<u8>)> {
+        std::mem::take(&mut self.subnegotiations)
+    }
+
+    // Sends any queued commands. Only the bytes which were actually written
+    // are removed from the queue, so this is safe to cancel.
+    pub async fn flush_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let n = self.stream.write(&self.pending).await?;
+            self.pending.drain(..n);
+        }
+        Ok(())
+    }
+
+    // Reads data from the server, handling any telnet commands found along the way.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let PortIo::Read(n) = self.read_or_send(buf).await? {
+                return Ok(n);
+            }
+        }
+    }
+
+    // Reads data from the server while sending the queued data and commands,
+    // so that a server which isn't reading doesn't stop data from being read.
+    // Returns PortIo::Written once some of the queue has been sent, so that
+    // more can be queued. This is safe to cancel.
+    pub async fn read_or_send(&mut self, buf: &mut [u8]) -> io::Result<PortIo> {
+        loop {
+            let n = if self.pending.is_empty() {
+                self.stream.read(buf).await?
+            } else {
+                let (mut rx, mut tx) = self.stream.split();
+                match read_or_write(&mut rx, &mut tx, buf, &self.pending).await? {
+                    PortIo::Read(n) => n,
+                    PortIo::Written(n) => {
+                        self.pending.drain(..n);
+                        return Ok(PortIo::Written(n));
+                    }
+                }
+            };
+            if n == 0 {
+                return Ok(PortIo::Read(0));
+            }
+            let (mut len, events) = self.parser.decode(&mut buf[..n]);
+            if !self.options.remote_enabled(OPT_BINARY) {
+                len = self.strip_cr_nul(&mut buf[..len]);
+            }
+            for event in events {
+                if let TelnetEvent::Subnegotiation(option, payload) = event {
+                    self.subnegotiations.push((option, payload));
+                } else if let Some(reply) = self.options.negotiate(&event) {
+                    self.pending.extend_from_slice(&reply);
+                }
+            }
+            if len > 0 {
+                return Ok(PortIo::Read(len));
+            }
+        }
+    }
+
+    // Queues as much of data as there's room for, to be sent to the server
+    // with the next read or write. Returns the number of bytes taken.
+    pub fn queue_data(&mut self, data: &[u8]) -> usize {
+        // Escaping at most doubles the size of the data.
+        let mut len = data
+            .len()
+            .min(MAX_PENDING.saturating_sub(self.pending.len()) / 2);
+        // A CR is kept with the byte after it, which decides how it's sent.
+        if len < data.len() && len > 0 && data[len - 1] == b'\r' {
+            len -= 1;
+        }
+        if self.options.local_enabled(OPT_BINARY) {
+            self.pending.extend_from_slice(&escape(&data[..len]));
+        } else {
+            self.pending
+                .extend_from_slice(&escape(&nvt_encode(&data[..len])));
+        }
+        len
+    }
+
+    // Sends data to the server.
+    pub async fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let n = self.queue_data(data);
+            data = &data[n..];
+            self.flush_pending().await?;
+        }
+        Ok(())
+    }
+
+    // Outside of binary mode a bare CR is sent as CR NUL, so the NUL needs
+    // to be removed. Returns the number of bytes remaining in data.
+    fn strip_cr_nul(&mut self, data: &mut [u8]) -> usize {
+        let mut len = 0;
+        for i in 0..data.len() {
+            let byte = data[i];
+            if !(self.last_was_cr && byte == 0) {
+                data[len] = byte;
+                len += 1;
+            }
+            self.last_was_cr = byte == b'\r';
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_doubles_iac() {
+        assert_eq!(escape(b"a\xffb"), b"a\xff\xffb");
+        assert_eq!(escape(b"abc"), b"abc");
+    }
+
+    #[test]
+    fn decode_unescapes_iac() {
+        let mut parser = TelnetParser::new();
+        let mut data = escape(b"a\xff\xffb\xff");
+        let (len, events) = parser.decode(&mut data);
+        assert_eq!(&data[..len], b"a\xff\xffb\xff");
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn decode_removes_commands() {
+        let mut parser = TelnetParser::new();
+        let mut data = vec![b'a', IAC, DO, OPT_BINARY, b'b', IAC, 241, b'c'];
+        let (len, events) = parser.decode(&mut data);
+        assert_eq!(&data[..len], b"abc");
+        assert_eq!(events, vec![TelnetEvent::Do(OPT_BINARY)]);
+    }
+
+    #[test]
+    fn subnegotiation_split_across_reads() {
+        let mut parser = TelnetParser::new();
+        let mut command = subnegotiation(OPT_COM_PORT, &[1, 0, 0, 0xff, 0]);
+        command.push(b'x');
+
+        let mut events = Vec::new();
+        let mut data = Vec::new();
+        for chunk in command.chunks_mut(2) {
+            let (len, mut chunk_events) = parser.decode(chunk);
+            data.extend_from_slice(&chunk[..len]);
+            events.append(&mut chunk_events);
+        }
+        assert_eq!(data, b"x");
+        assert_eq!(
+            events,
+            vec![TelnetEvent::Subnegotiation(
+                OPT_COM_PORT,
+                vec![1, 0, 0, 0xff, 0]
+            )]
+        );
+    }
+
+    #[test]
+    fn negotiate_supported_options() {
+        let mut options = TelnetOptions::new(&[OPT_BINARY], &[OPT_SGA]);
+        assert_eq!(
+            options.negotiate(&TelnetEvent::Do(OPT_BINARY)),
+            Some([IAC, WILL, OPT_BINARY])
+        );
+        assert!(options.local_enabled(OPT_BINARY));
+        assert_eq!(
+            options.negotiate(&TelnetEvent::Will(OPT_SGA)),
+            Some([IAC, DO, OPT_SGA])
+        );
+        assert!(options.remote_enabled(OPT_SGA));
+        // Repeating an option which is already enabled doesn't get a reply.
+        assert_eq!(options.negotiate(&TelnetEvent::Do(OPT_BINARY)), None);
+        assert_eq!(
+            options.negotiate(&TelnetEvent::Dont(OPT_BINARY)),
+            Some([IAC, WONT, OPT_BINARY])
+        );
+        assert!(!options.local_enabled(OPT_BINARY));
+    }
+
+    #[test]
+    fn negotiate_refuses_unsupported_options() {
+        let mut options = TelnetOptions::new(&[OPT_BINARY], &[OPT_SGA]);
+        assert_eq!(
+            options.negotiate(&TelnetEvent::Do(OPT_ECHO)),
+            Some([IAC, WONT, OPT_ECHO])
+        );
+        assert_eq!(
+            options.negotiate(&TelnetEvent::Will(OPT_ECHO)),
+            Some([IAC, DONT, OPT_ECHO])
+        );
+        assert!(!options.local_enabled(OPT_ECHO));
+        assert!(!options.remote_enabled(OPT_ECHO));
+    }
+
+    #[test]
+    fn negotiate_answers_to_offer() {
+        let mut options = TelnetOptions::new(&[OPT_BINARY], &[OPT_SGA]);
+        assert_eq!(
+            options.offer(),
+            vec![IAC, WILL, OPT_BINARY, IAC, DO, OPT_SGA]
+        );
+        // Answers to our own offers are not acknowledged, which would loop.
+        assert_eq!(options.negotiate(&TelnetEvent::Do(OPT_BINARY)), None);
+        assert_eq!(options.negotiate(&TelnetEvent::Will(OPT_SGA)), None);
+        assert!(options.local_enabled(OPT_BINARY));
+        assert!(options.remote_enabled(OPT_SGA));
+    }
+
+    #[test]
+    fn nvt_encode_pads_bare_cr() {
+        assert_eq!(nvt_encode(b"a\rb\r\n\r"), b"a\r\0b\r\n\r\0");
+    }
+}
@@ -0,0 +1,301 @@
+use crate::error::{ProgramError, Result};
+use crate::rfc2217;
+use crate::telnet::{TelnetStream, OPT_BINARY, OPT_COM_PORT, OPT_ECHO, OPT_SGA};
+use futures::{future::FutureExt, pin_mut, select};
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_serial::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+/// A change to the line settings or control lines of a port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineCommand {
+    Baud(u32),
+    DataBits(DataBits),
+    Parity(Parity),
+    StopBits(StopBits),
+    FlowControl(FlowControl),
+    Break(bool),
+    Dtr(bool),
+    Rts(bool),
+    Purge(ClearBuffer),
+}
+
+/// The current line settings of a port.
+#[derive(Clone, Debug)]
+pub struct LineSettings {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// State of the DTR line, or None if it hasn't been set.
+    pub dtr: Option<bool>,
+    /// State of the RTS line, or None if it hasn't been set.
+    pub rts: Option<bool>,
+    /// Whether a break condition is being sent.
+    pub break_on: bool,
+}
+
+impl LineSettings {
+    // Records the effect of cmd on the settings.
+    pub fn apply(&mut self, cmd: &LineCommand) {
+        match *cmd {
+            LineCommand::Baud(baud) => self.baud = baud,
+            LineCommand::DataBits(data_bits) => self.data_bits = data_bits,
+            LineCommand::Parity(parity) => self.parity = parity,
+            LineCommand::StopBits(stop_bits) => self.stop_bits = stop_bits,
+            LineCommand::FlowControl(flow_control) => self.flow_control = flow_control,
+            LineCommand::Dtr(on) => self.dtr = Some(on),
+            LineCommand::Rts(on) => self.rts = Some(on),
+            LineCommand::Break(on) => self.break_on = on,
+            LineCommand::Purge(_) => {}
+        }
+    }
+
+    // Returns the commands needed to configure a port with these settings.
+    pub fn commands(&self) -> Vec<LineCommand> {
+        let mut commands = vec![
+            LineCommand::Baud(self.baud),
+            LineCommand::DataBits(self.data_bits),
+            LineCommand::Parity(self.parity),
+            LineCommand::StopBits(self.stop_bits),
+            LineCommand::FlowControl(self.flow_control),
+        ];
+        if let Some(dtr) = self.dtr {
+            commands.push(LineCommand::Dtr(dtr));
+        }
+        if let Some(rts) = self.rts {
+            commands.push(LineCommand::Rts(rts));
+        }
+        commands
+    }
+}
+
+// Formats the settings in the usual shorthand (i.e. 115200 8N1).
+impl fmt::Display for LineSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud, data_bits, parity, stop_bits)
+    }
+}
+
+/// What happened when reading from a port while writing to it.
+pub enum PortIo {
+    /// This many bytes were read (0 means that the port was closed).
+    Read(usize),
+    /// This many bytes of the data were written.
+    Written(usize),
+}
+
+// Reads from rx while writing as much of data as it will take to tx, and
+// returns whichever happens first. Neither is left half done if the other
+// wins, so this is safe to cancel.
+pub async fn read_or_write<R, W>(
+    rx: &mut R,
+    tx: &mut W,
+    buf: &mut [u8],
+    data: &[u8],
+) -> io::Result<PortIo>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let read_event = rx.read(buf).fuse();
+    let write_event = tx.write(data).fuse();
+    pin_mut!(read_event, write_event);
+
+    select! {
+        n = read_event => Ok(PortIo::Read(n?)),
+        n = write_event => Ok(PortIo::Written(n?)),
+    }
+}
+
+/// The connection which monitor talks to.
+pub enum Transport {
+    /// A local serial port.
+    Serial(tokio_serial::SerialStream),
+    /// A remote serial port, controlled using RFC 2217.
+    Rfc2217(Box<TelnetStream>),
+    /// A raw TCP connection (i.e. to a console server).
+    Tcp(TcpStream),
+    /// A telnet connection.
+    Telnet(Box<TelnetStream>),
+}
+
+impl Transport {
+    // Opens either a remote port (if name is a URL) or a local serial port.
+    pub async fn open(name: &str, settings: &LineSettings) -> Result<Transport> {
+        if name.contains("://") {
+            Transport::open_url(name, settings).await
+        } else {
+            Transport::open_serial(name, settings)
+        }
+    }
+
+    // Opens a local serial port.
+    pub fn open_serial(port_name: &str, settings: &LineSettings) -> Result<Transport> {
+        let port_builder = tokio_serial::new(port_name, settings.baud)
+            .data_bits(settings.data_bits)
+            .parity(settings.parity)
+            .stop_bits(settings.stop_bits)
+            .flow_control(settings.flow_control);
+
+        let port = tokio_serial::SerialStream::open(&port_builder)
+            .map_err(|e| ProgramError::UnableToOpen(port_name.to_string(), e.into()))?;
+        let mut transport = Transport::Serial(port);
+        // DTR and RTS are only set if they were changed during an earlier connection.
+        if let Some(dtr) = settings.dtr {
+            transport.apply(&LineCommand::Dtr(dtr))?;
+        }
+        if let Some(rts) = settings.rts {
+            transport.apply(&LineCommand::Rts(rts))?;
+        }
+        Ok(transport)
+    }
+
+    // Opens a remote port described by a URL (i.e. rfc2217://host:port,
+    // tcp://host:port or telnet://host:port).
+    pub async fn open_url(url: &str, settings: &LineSettings) -> Result<Transport> {
+        let open_err = |e| ProgramError::UnableToOpen(url.to_string(), e);
+        if let Some(addr) = url.strip_prefix("tcp://") {
+            let stream = TcpStream::connect(addr).await.map_err(open_err)?;
+            Ok(Transport::Tcp(stream))
+        } else if let Some(addr) = url.strip_prefix("telnet://") {
+            let stream = TelnetStream::connect(
+                addr,
+                &[OPT_BINARY, OPT_SGA],
+                &[OPT_BINARY, OPT_SGA, OPT_ECHO],
+            )
+            .await
+            .map_err(open_err)?;
+            Ok(Transport::Telnet(Box::new(stream)))
+        } else if let Some(addr) = url.strip_prefix("rfc2217://") {
+            let mut stream = TelnetStream::connect(
+                addr,
+                &[OPT_BINARY, OPT_SGA, OPT_COM_PORT],
+                &[OPT_BINARY, OPT_SGA, OPT_ECHO],
+            )
+            .await
+            .map_err(open_err)?;
+            for cmd in settings.commands() {
+                stream.queue_command(&rfc2217::client_request(&cmd));
+            }
+            Ok(Transport::Rfc2217(Box::new(stream)))
+        } else {
+            Err(ProgramError::UnsupportedUrl(url.to_string()))
+        }
+    }
+
+    // Reads data from the port.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Serial(port) => port.read(buf).await,
+            Transport::Rfc2217(stream) => {
+                let n = stream.read(buf).await?;
+                // The replies to our settings and any line/modem state
+                // notifications aren't needed.
+                stream.take_subnegotiations();
+                Ok(n)
+            }
+            Transport::Tcp(stream) => stream.read(buf).await,
+            Transport::Telnet(stream) => {
+                let n = stream.read(buf).await?;
+                stream.take_subnegotiations();
+                Ok(n)
+            }
+        }
+    }
+
+    // Reads data from the port while writing data to it, so that a port which
+    // isn't taking data (i.e. because of flow control) doesn't stop data from
+    // being read. This is safe to cancel.
+    pub async fn read_or_write(&mut self, buf: &mut [u8], data: &[u8]) -> io::Result<PortIo> {
+        if data.is_empty() {
+            return Ok(PortIo::Read(self.read(buf).await?));
+        }
+        match self {
+            Transport::Serial(port) => {
+                let (mut rx, mut tx) = tokio::io::split(port);
+                read_or_write(&mut rx, &mut tx, buf, data).await
+            }
+            // Telnet data is queued along with the telnet commands, which
+            // are sent while reading. When the queue is full, nothing more is
+            // taken until some of it has been sent.
+            Transport::Rfc2217(stream) | Transport::Telnet(stream) => {
+                let n = stream.queue_data(data);
+                if n > 0 {
+                    return Ok(PortIo::Written(n));
+                }
+                match stream.read_or_send(buf).await? {
+                    PortIo::Read(n) => {
+                        stream.take_subnegotiations();
+                        Ok(PortIo::Read(n))
+                    }
+                    PortIo::Written(_) => Ok(PortIo::Written(0)),
+                }
+            }
+            Transport::Tcp(stream) => {
+                let (mut rx, mut tx) = stream.split();
+                read_or_write(&mut rx, &mut tx, buf, data).await
+            }
+        }
+    }
+
+    // Writes data to the port.
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Transport::Serial(port) => AsyncWriteExt::write_all(port, data).await,
+            Transport::Rfc2217(stream) | Transport::Telnet(stream) => stream.write_all(data).await,
+            Transport::Tcp(stream) => stream.write_all(data).await,
+        }
+    }
+
+    // Returns the state of the CTS line, or None if it can't be read (which is
+    // always the case for remote ports).
+    pub fn cts(&mut self) -> Option<bool> {
+        match self {
+            Transport::Serial(port) => port.read_clear_to_send().ok(),
+            _ => None,
+        }
+    }
+
+    // Changes the line settings of the port. For remote ports the command is
+    // sent along with the next read or write.
+    pub fn apply(&mut self, cmd: &LineCommand) -> Result<()> {
+        match self {
+            Transport::Serial(port) => match *cmd {
+                LineCommand::Baud(baud) => port.set_baud_rate(baud)?,
+                LineCommand::DataBits(data_bits) => port.set_data_bits(data_bits)?,
+                LineCommand::Parity(parity) => port.set_parity(parity)?,
+                LineCommand::StopBits(stop_bits) => port.set_stop_bits(stop_bits)?,
+                LineCommand::FlowControl(flow_control) => port.set_flow_control(flow_control)?,
+                LineCommand::Break(true) => port.set_break()?,
+                LineCommand::Break(false) => port.clear_break()?,
+                LineCommand::Dtr(on) => port.write_data_terminal_ready(on)?,
+                LineCommand::Rts(on) => port.write_request_to_send(on)?,
+                LineCommand::Purge(buffer) => port.clear(buffer)?,
+            },
+            Transport::Rfc2217(stream) => stream.queue_command(&rfc2217::client_request(cmd)),
+            Transport::Tcp(_) | Transport::Telnet(_) => {
+                return Err(ProgramError::LineSettingsNotSupported)
+            }
+        }
+        Ok(())
+    }
+}
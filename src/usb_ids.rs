@@ -0,0 +1,53 @@
+// A small table of the USB IDs used by common USB serial chips and boards, so
+// that devices which don't report a product string can still be recognized.
+// Entries without a PID are used for any device from that vendor which isn't
+// listed separately.
+#[rustfmt::skip]
+const USB_IDS: &[(u16, Option<u16>, &str)] = &[
+    (0x0403, Some(0x6001), "FTDI FT232R"),
+    (0x0403, Some(0x6010), "FTDI FT2232H"),
+    (0x0403, Some(0x6011), "FTDI FT4232H"),
+    (0x0403, Some(0x6014), "FTDI FT232H"),
+    (0x0403, Some(0x6015), "FTDI FT230X/FT231X"),
+    (0x0403, None, "FTDI"),
+    (0x0483, Some(0x374b), "STMicroelectronics ST-LINK/V2-1"),
+    (0x0483, Some(0x374e), "STMicroelectronics STLINK-V3"),
+    (0x0483, Some(0x3752), "STMicroelectronics ST-LINK/V2-1"),
+    (0x0483, Some(0x5740), "STMicroelectronics STM32 Virtual COM Port"),
+    (0x0483, None, "STMicroelectronics"),
+    (0x067b, Some(0x2303), "Prolific PL2303"),
+    (0x067b, Some(0x23a3), "Prolific PL2303GC"),
+    (0x067b, None, "Prolific"),
+    (0x10c4, Some(0xea60), "Silicon Labs CP2102/CP2102N/CP2104"),
+    (0x10c4, Some(0xea70), "Silicon Labs CP2105"),
+    (0x10c4, Some(0xea71), "Silicon Labs CP2108"),
+    (0x10c4, None, "Silicon Labs"),
+    (0x1a86, Some(0x5523), "WCH CH341"),
+    (0x1a86, Some(0x55d3), "WCH CH343"),
+    (0x1a86, Some(0x55d4), "WCH CH9102"),
+    (0x1a86, Some(0x7523), "WCH CH340"),
+    (0x1a86, None, "WCH"),
+    (0x2e8a, Some(0x0005), "Raspberry Pi RP2040 MicroPython"),
+    (0x2e8a, Some(0x000a), "Raspberry Pi RP2040 Pico SDK"),
+    (0x2e8a, Some(0x000c), "Raspberry Pi Debug Probe"),
+    (0x2e8a, None, "Raspberry Pi"),
+    (0x303a, Some(0x0002), "Espressif ESP32-S2"),
+    (0x303a, Some(0x0009), "Espressif ESP32-S3"),
+    (0x303a, Some(0x1001), "Espressif ESP32-S3/C3 USB Serial/JTAG"),
+    (0x303a, None, "Espressif"),
+    (0xf055, Some(0x9800), "MicroPython Pyboard"),
+    (0xf055, None, "MicroPython"),
+];
+
+// Returns the name of the chip (or at least the vendor) for a VID/PID pair.
+pub fn chip_name(vid: u16, pid: u16) -> Option<&'static str> {
+    USB_IDS
+        .iter()
+        .find(|(id_vid, id_pid, _)| *id_vid == vid && *id_pid == Some(pid))
+        .or_else(|| {
+            USB_IDS
+                .iter()
+                .find(|(id_vid, id_pid, _)| *id_vid == vid && id_pid.is_none())
+        })
+        .map(|(_, _, name)| *name)
+}
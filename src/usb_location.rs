@@ -0,0 +1,113 @@
+use serialport::UsbPortInfo;
+
+/// Where a USB serial port is plugged in. This is what tells apart the ports
+/// of composite devices, and identical devices, which otherwise show the same
+/// VID/PID and serial number.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsbLocation {
+    /// The bus and hub port path of the USB device (i.e. 1-3.2 is bus 1, port 2
+    /// of the hub plugged into port 3).
+    pub location: Option<String>,
+    /// The number of the USB interface that the port belongs to.
+    pub interface: Option<u8>,
+    /// The sysfs path of the device (Linux only).
+    pub sysfs_path: Option<String>,
+}
+
+// Splits the name of a USB interface directory in sysfs (i.e. 1-3.2:1.0,
+// which is <bus>-<ports>:<config>.<interface>) into the location of the
+// device and the interface number.
+#[cfg(target_os = "linux")]
+fn parse_interface_dir(name: &str) -> Option<(String, u8)> {
+    let (location, config_interface) = name.split_once(':')?;
+    let (bus, ports) = location.split_once('-')?;
+    let (config, interface) = config_interface.split_once('.')?;
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(bus)
+        || !ports.split('.').all(is_number)
+        || !is_number(config)
+        || !is_number(interface)
+    {
+        return None;
+    }
+    Some((location.to_string(), interface.parse().ok()?))
+}
+
+// Looks up where the port is plugged in by following its entry in sysfs.
+#[cfg(target_os = "linux")]
+fn sysfs_location(port_name: &str) -> UsbLocation {
+    let name = match std::path::Path::new(port_name).file_name() {
+        Some(name) => name,
+        None => return UsbLocation::default(),
+    };
+    let device = std::path::Path::new("/sys/class/tty")
+        .join(name)
+        .join("device");
+    let sysfs_path = match std::fs::canonicalize(device) {
+        Ok(path) => path,
+        Err(_) => return UsbLocation::default(),
+    };
+    // The tty device is either the USB interface (ttyACM) or a child of it (ttyUSB).
+    let interface_dir = sysfs_path.ancestors().find_map(|dir| {
+        dir.file_name()
+            .and_then(|name| parse_interface_dir(&name.to_string_lossy()))
+    });
+    UsbLocation {
+        location: interface_dir.as_ref().map(|(location, _)| location.clone()),
+        interface: interface_dir.map(|(_, interface)| interface),
+        sysfs_path: Some(sysfs_path.to_string_lossy().into_owned()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sysfs_location(_port_name: &str) -> UsbLocation {
+    UsbLocation::default()
+}
+
+// Returns where a USB serial port is plugged in. The interface number comes
+// from the port enumeration when it's available, and from sysfs otherwise.
+pub fn usb_location(port_name: &str, info: &UsbPortInfo) -> UsbLocation {
+    let mut location = sysfs_location(port_name);
+    if info.interface.is_some() {
+        location.interface = info.interface;
+    }
+    location
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interface_dirs() {
+        let interface = |location: &str, interface| Some((location.to_string(), interface));
+        assert_eq!(parse_interface_dir("1-3:1.0"), interface("1-3", 0));
+        assert_eq!(parse_interface_dir("1-3.2:1.2"), interface("1-3.2", 2));
+        assert_eq!(
+            parse_interface_dir("3-1.4.1:2.10"),
+            interface("3-1.4.1", 10)
+        );
+
+        let not_interfaces = [
+            "usb1",
+            "1-3",
+            "1-3.2",
+            "ttyACM0",
+            "1-3:1",
+            "1-3:1.",
+            "1-3:1.x",
+            "1-3:1.+0",
+            "1-3:1.256",
+            "1-:1.0",
+            "-3:1.0",
+            "1-3..2:1.0",
+            "1-3.2.:1.0",
+            "a-3:1.0",
+            "1-3:a.0",
+            "1-3:1.0:1.0",
+        ];
+        for name in not_interfaces {
+            assert_eq!(parse_interface_dir(name), None, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::error::Result;
+use crate::merged_log::timestamp;
+use crate::usb_ids::chip_name;
+use crate::usb_location::usb_location;
+use crate::{matching_ports, port_description, Opt};
+use futures::{future::FutureExt, pin_mut, select};
+use mio_serial::SerialPortInfo;
+use serialport::SerialPortType;
+use std::time::Duration;
+
+// How often the list of ports is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// A port which has been seen, along with its details, which are looked up
+// when it's first seen, since they can't be looked up once it's removed.
+struct SeenPort {
+    /// The port as it was enumerated.
+    info: SerialPortInfo,
+    /// The description of the port, as shown by --list.
+    description: String,
+    /// The details of the port which are included in each JSON event.
+    details: serde_json::Value,
+}
+
+impl SeenPort {
+    fn new(port: &SerialPortInfo) -> Self {
+        SeenPort {
+            info: port.clone(),
+            description: port_description(port),
+            details: json_details(port),
+        }
+    }
+}
+
+// Returns the details of a port, which are included in each JSON event.
+fn json_details(port: &SerialPortInfo) -> serde_json::Value {
+    let mut value = serde_json::json!({
+        "port": port.port_name,
+    });
+    if let SerialPortType::UsbPort(info) = &port.port_type {
+        value["vid"] = format!("{:04x}", info.vid).into();
+        value["pid"] = format!("{:04x}", info.pid).into();
+        value["manufacturer"] = info.manufacturer.clone().into();
+        value["serial"] = info.serial_number.clone().into();
+        value["product"] = info.product.clone().into();
+        value["chip"] = chip_name(info.vid, info.pid).into();
+        let location = usb_location(&port.port_name, info);
+        value["interface"] = location.interface.into();
+        value["location"] = location.location.into();
+        value["sysfs_path"] = location.sysfs_path.into();
+    }
+    value
+}
+
+// Formats a change to the list of ports as a line of JSON.
+fn json_event(event: &str, port: &SeenPort) -> String {
+    let mut value = port.details.clone();
+    value["time"] = timestamp().into();
+    value["event"] = event.into();
+    value.to_string()
+}
+
+// Reports a change to the list of ports.
+fn report(event: &str, port: &SeenPort, opt: &Opt) {
+    if opt.json {
+        println!("{}", json_event(event, port));
+    } else {
+        println!("{} {} {}", timestamp(), event, port.description);
+    }
+}
+
+// Lists the ports which match the filtering criteria, and then keeps
+// watching, reporting ports as they're added and removed. This runs until
+// Control-C is pressed.
+pub async fn watch_ports(opt: &Opt) -> Result<()> {
+    let mut ports: Vec<SeenPort> = matching_ports(opt)?.iter().map(SeenPort::new).collect();
+    for port in &ports {
+        report("present", port, opt);
+    }
+    eprintln!("Watching for changes, press Control-C to exit");
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut last_error = None;
+    // Control-C is only listened for once, so that a press while the ports
+    // are being checked isn't missed.
+    let ctrl_c = tokio::signal::ctrl_c().fuse();
+    pin_mut!(ctrl_c);
+
+    loop {
+        let interrupted = {
+            let tick = interval.tick().fuse();
+            pin_mut!(tick);
+
+            select! {
+                _ = tick => false,
+                _ = ctrl_c => true,
+            }
+        };
+        if interrupted {
+            return Ok(());
+        }
+        // A failure to list the ports (which can happen while a device is
+        // being plugged in) is reported once, and then retried.
+        let new_ports = match matching_ports(opt) {
+            Ok(new_ports) => {
+                last_error = None;
+                new_ports
+            }
+            Err(e) => {
+                let error = e.to_string();
+                if last_error.as_ref() != Some(&error) {
+                    eprintln!("{} Unable to list ports: {}", timestamp(), error);
+                    last_error = Some(error);
+                }
+                continue;
+            }
+        };
+        // A port which is renumbered (or replaced by a different device with
+        // the same name) shows up as being removed and then added. Removed
+        // ports are reported using the details from when they were last
+        // seen, since the device has gone by now.
+        let (kept, removed): (Vec<_>, Vec<_>) = ports
+            .into_iter()
+            .partition(|port| new_ports.contains(&port.info));
+        for port in &removed {
+            report("removed", port, opt);
+        }
+        ports = kept;
+        for port in &new_ports {
+            if !ports.iter().any(|seen| seen.info == *port) {
+                let port = SeenPort::new(port);
+                report("added", &port, opt);
+                ports.push(port);
+            }
+        }
+    }
+}